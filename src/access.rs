@@ -0,0 +1,100 @@
+use tg_flows::{ChatId, UserId};
+
+fn parse_ids<T: std::str::FromStr>(name: &str) -> Vec<T> {
+    std::env::var(name)
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|id| id.trim().parse().ok())
+        .collect()
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum AccessScope {
+    Chat(ChatId),
+    User(UserId),
+}
+
+impl AccessScope {
+    fn key(&self) -> String {
+        match self {
+            AccessScope::Chat(id) => format!("access--chat-{}", id),
+            AccessScope::User(id) => format!("access--user-{}", id),
+        }
+    }
+
+    /// `Some(true)` if allowed at runtime, `Some(false)` if banned.
+    fn get(&self) -> Option<bool> {
+        store_flows::get(&self.key()).and_then(|v| v.as_bool())
+    }
+
+    pub fn set(&self, allowed: bool) {
+        log::info!("set access, key: {}, allowed: {}", self.key(), allowed);
+        store_flows::set(&self.key(), serde_json::Value::Bool(allowed), None);
+    }
+}
+
+/// Decides who can use the bot. The allowlists come from the `allowed_users`
+/// and `allowed_chats` environment variables, group chats are allowed by
+/// their chat ids. More users and chats can be allowed with the invite codes
+/// in `invite_codes`. Without any of them everyone can use the bot.
+pub struct AccessControl {
+    users: Vec<u64>,
+    chats: Vec<i64>,
+    invite_codes: Vec<String>,
+}
+
+impl AccessControl {
+    pub fn from_env() -> Self {
+        Self {
+            users: parse_ids("allowed_users"),
+            chats: parse_ids("allowed_chats"),
+            invite_codes: std::env::var("invite_codes")
+                .unwrap_or_default()
+                .split(',')
+                .map(|code| code.trim().to_owned())
+                .filter(|code| !code.is_empty())
+                .collect(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.users.is_empty() || !self.chats.is_empty() || !self.invite_codes.is_empty()
+    }
+
+    pub fn has_invite_codes(&self) -> bool {
+        !self.invite_codes.is_empty()
+    }
+
+    pub fn is_allowed(&self, chat_id: ChatId, user_id: Option<UserId>) -> bool {
+        let chat = AccessScope::Chat(chat_id).get();
+        let user = user_id.and_then(|id| AccessScope::User(id).get());
+        if chat == Some(false) || user == Some(false) {
+            return false;
+        }
+        if !self.is_enabled() {
+            return true;
+        }
+
+        chat == Some(true)
+            || user == Some(true)
+            || self.chats.contains(&chat_id.0)
+            || user_id
+                .map(|id| self.users.contains(&id.0))
+                .unwrap_or(false)
+    }
+
+    /// Redeem an invite code for the scope, every code can only be used once.
+    pub fn redeem(&self, code: &str, scope: AccessScope) -> bool {
+        if !self.invite_codes.iter().any(|c| c == code) {
+            return false;
+        }
+        let key = format!("invite--{}", code);
+        if store_flows::get(&key).is_some() {
+            log::info!("invite code has been used: {}", code);
+            return false;
+        }
+        store_flows::set(&key, serde_json::Value::String(scope.key()), None);
+        scope.set(true);
+        true
+    }
+}
@@ -0,0 +1,205 @@
+use std::fmt;
+
+use nom::{
+    branch::alt,
+    bytes::complete::{take_while1, take_while_m_n},
+    character::complete::{char, multispace1},
+    combinator::{eof, opt, peek},
+    sequence::{pair, preceded},
+    IResult,
+};
+use tg_flows::BotCommand;
+
+/// Telegram limits the name of a command to 32 characters.
+const COMMAND_NAME_LIMIT: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TgBotCommand {
+    Ask,
+    Nihongo,
+    Reset,
+    New,
+    Usage,
+    Settings,
+    Help,
+    Admin,
+    Invite,
+    Start,
+}
+
+impl TgBotCommand {
+    pub fn root_commands() -> Vec<TgBotCommand> {
+        vec![
+            TgBotCommand::Ask,
+            TgBotCommand::Nihongo,
+            TgBotCommand::Reset,
+            TgBotCommand::New,
+            TgBotCommand::Usage,
+            TgBotCommand::Settings,
+            TgBotCommand::Help,
+        ]
+    }
+
+    pub fn admin_commands() -> Vec<TgBotCommand> {
+        let mut commands = Self::root_commands();
+        commands.push(TgBotCommand::Admin);
+        commands
+    }
+
+    /// Look up a command by its name or one of its aliases, ignoring case.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "ask" | "q" => Some(TgBotCommand::Ask),
+            "nihongo" | "jp" => Some(TgBotCommand::Nihongo),
+            "reset" | "clear" => Some(TgBotCommand::Reset),
+            "new" => Some(TgBotCommand::New),
+            "usage" | "quota" => Some(TgBotCommand::Usage),
+            "settings" | "setting" => Some(TgBotCommand::Settings),
+            "help" => Some(TgBotCommand::Help),
+            "admin" => Some(TgBotCommand::Admin),
+            "invite" => Some(TgBotCommand::Invite),
+            "start" => Some(TgBotCommand::Start),
+            _ => None,
+        }
+    }
+}
+
+impl From<TgBotCommand> for BotCommand {
+    fn from(val: TgBotCommand) -> Self {
+        match val {
+            TgBotCommand::Ask => BotCommand::new("ask", "ask any questions"),
+            TgBotCommand::Nihongo => {
+                BotCommand::new("nihongo", "learn japanese by sentences and questions")
+            }
+            TgBotCommand::Reset => {
+                BotCommand::new("reset", "restart the conversation you are replying to")
+            }
+            TgBotCommand::New => {
+                BotCommand::new("new", "start a new conversation with the same persona")
+            }
+            TgBotCommand::Usage => BotCommand::new("usage", "show your usage and quotas"),
+            TgBotCommand::Settings => BotCommand::new("settings", "adjust settings of the bot"),
+            TgBotCommand::Help => BotCommand::new("help", "show help messages"),
+            TgBotCommand::Admin => BotCommand::new(
+                "admin",
+                "stats, setmodel <model>, allow <id>, ban <id>, broadcast <text>, prompt show|set <text>",
+            ),
+            TgBotCommand::Invite => BotCommand::new("invite", "use an invite code"),
+            TgBotCommand::Start => BotCommand::new("start", "start using the bot"),
+        }
+    }
+}
+
+impl fmt::Display for TgBotCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let cmd: BotCommand = (*self).into();
+        write!(f, "/{} {}", cmd.command, cmd.description)
+    }
+}
+
+/// A command parsed from a message, e.g. `/ask@MyBot how are you`.
+#[derive(Debug, PartialEq)]
+pub struct ParsedCommand<'a> {
+    /// `None` if the name isn't a command of the bot.
+    pub command: Option<TgBotCommand>,
+    pub name: &'a str,
+    pub username: Option<&'a str>,
+    pub args: &'a str,
+}
+
+impl<'a> ParsedCommand<'a> {
+    /// Whether the command is addressed to the bot, a command without the
+    /// `@botname` suffix is for every bot in the chat.
+    pub fn is_for(&self, bot_username: Option<&str>) -> bool {
+        match (self.username, bot_username) {
+            (Some(username), Some(bot_username)) => username.eq_ignore_ascii_case(bot_username),
+            _ => true,
+        }
+    }
+}
+
+fn is_command_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+fn parse_command_name(input: &str) -> IResult<&str, &str> {
+    preceded(
+        char('/'),
+        take_while_m_n(1, COMMAND_NAME_LIMIT, is_command_char),
+    )(input)
+}
+
+fn parse_username(input: &str) -> IResult<&str, &str> {
+    preceded(char('@'), take_while1(is_command_char))(input)
+}
+
+fn parse_command_parts(input: &str) -> IResult<&str, (&str, Option<&str>)> {
+    let (input, parts) = pair(parse_command_name, opt(parse_username))(input)?;
+    // a command ends at the end of the text or a whitespace, `/ask-me` isn't `/ask`
+    let (input, _) = peek(alt((eof, multispace1)))(input)?;
+    Ok((input, parts))
+}
+
+/// Parse a command at the beginning of a message, arguments are the rest of
+/// the message, which can be separated from the command by spaces or newlines.
+pub fn parse_command(text: &str) -> Option<ParsedCommand<'_>> {
+    let (args, (name, username)) = parse_command_parts(text).ok()?;
+    Some(ParsedCommand {
+        command: TgBotCommand::from_name(name),
+        name,
+        username,
+        args: args.trim(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_command_with_username() {
+        let parsed = parse_command("/ask@Bot q").unwrap();
+        assert_eq!(parsed.command, Some(TgBotCommand::Ask));
+        assert_eq!(parsed.username, Some("Bot"));
+        assert_eq!(parsed.args, "q");
+        assert!(parsed.is_for(Some("bot")));
+        assert!(parsed.is_for(None));
+    }
+
+    #[test]
+    fn parse_command_for_other_bot() {
+        let parsed = parse_command("/ask@OtherBot q").unwrap();
+        assert_eq!(parsed.command, Some(TgBotCommand::Ask));
+        assert_eq!(parsed.username, Some("OtherBot"));
+        assert!(!parsed.is_for(Some("Bot")));
+    }
+
+    #[test]
+    fn parse_command_unknown_name() {
+        let parsed = parse_command("/askfoo").unwrap();
+        assert_eq!(parsed.command, None);
+        assert_eq!(parsed.name, "askfoo");
+        assert_eq!(parsed.args, "");
+    }
+
+    #[test]
+    fn parse_command_ignores_case() {
+        let parsed = parse_command("/ASK").unwrap();
+        assert_eq!(parsed.command, Some(TgBotCommand::Ask));
+        assert_eq!(parsed.name, "ASK");
+    }
+
+    #[test]
+    fn parse_command_args_after_newline() {
+        let parsed = parse_command("/ask\nwhat is\nthis").unwrap();
+        assert_eq!(parsed.command, Some(TgBotCommand::Ask));
+        assert_eq!(parsed.args, "what is\nthis");
+    }
+
+    #[test]
+    fn parse_command_needs_boundary() {
+        assert_eq!(parse_command("/ask-me"), None);
+        assert_eq!(parse_command("ask q"), None);
+        assert_eq!(parse_command("/"), None);
+    }
+}
@@ -0,0 +1,306 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use crate::openaiext::ChatMessage;
+use crate::settings;
+use crate::tokens::{estimate_tokens, IMAGE_TOKENS, MESSAGE_OVERHEAD_TOKENS};
+
+/// Version of the schema of stored conversations, it's part of the keys so a
+/// new schema can migrate the older ones.
+pub const CONVERSATION_VERSION: u32 = 1;
+
+/// Tokens left in the context window of the model for the answer.
+pub const ANSWER_TOKENS: usize = 1024;
+
+/// Prompt summarizing the turns dropped from a conversation.
+const SUMMARY_PROMPT: &str = "Summarize the conversation between the user and the assistant \
+in no more than 200 words. Keep the facts, names and decisions the rest of the conversation may \
+refer to, and write in the language of the conversation.";
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    User,
+    Assistant,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Turn {
+    pub role: Role,
+    pub text: String,
+    /// Telegram file ids of the images of a user turn, older conversations
+    /// may keep `data:` urls instead.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<String>,
+    /// The Telegram message of the question, or of the first part of the
+    /// answer.
+    pub message_id: Option<i32>,
+    /// The other parts of a long answer.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub parts: Vec<i32>,
+    /// Unix timestamp in seconds.
+    pub timestamp: i64,
+    /// The model answering, only for answers.
+    pub model: Option<String>,
+    /// Estimated tokens of the turn.
+    pub tokens: usize,
+    /// Estimated tokens of the request of an answer.
+    pub prompt_tokens: Option<usize>,
+}
+
+impl Turn {
+    fn new(role: Role, text: &str, images: Vec<String>) -> Self {
+        // images are counted by the conversation, only some of them are sent
+        let tokens = estimate_tokens(text) + MESSAGE_OVERHEAD_TOKENS;
+        Self {
+            role,
+            text: text.to_owned(),
+            images,
+            message_id: None,
+            parts: vec![],
+            timestamp: Utc::now().timestamp(),
+            model: None,
+            tokens,
+            prompt_tokens: None,
+        }
+    }
+
+    pub fn user(text: &str, images: Vec<String>, message_id: i32) -> Self {
+        Self {
+            message_id: Some(message_id),
+            ..Self::new(Role::User, text, images)
+        }
+    }
+
+    /// An answer sent in the messages of `message_ids`, which are the parts
+    /// of the answer.
+    pub fn assistant(text: &str, message_ids: &[i32], model: &str, prompt_tokens: usize) -> Self {
+        Self {
+            message_id: message_ids.first().copied(),
+            parts: message_ids.iter().skip(1).copied().collect(),
+            model: Some(model.to_owned()),
+            prompt_tokens: Some(prompt_tokens),
+            ..Self::new(Role::Assistant, text, vec![])
+        }
+    }
+
+    pub fn message_ids(&self) -> Vec<i32> {
+        self.message_id
+            .iter()
+            .chain(self.parts.iter())
+            .copied()
+            .collect()
+    }
+
+    /// The message of the turn with the `data:` urls of its images, which are
+    /// left out when empty.
+    pub fn to_message(&self, images: &[String]) -> ChatMessage {
+        match self.role {
+            Role::User if !images.is_empty() => {
+                ChatMessage::user_with_images(self.text.as_str(), images.to_vec())
+            }
+            Role::User => ChatMessage::user(self.text.as_str()),
+            Role::Assistant => ChatMessage::assistant(self.text.as_str()),
+        }
+    }
+
+    fn transcript(&self) -> String {
+        let role = match self.role {
+            Role::User => "user",
+            Role::Assistant => "assistant",
+        };
+        format!("{}: {}", role, self.text)
+    }
+}
+
+/// The history of the conversation of a reply chain kept by the bot, the
+/// oldest turns are folded into a summary when they don't fit in the context
+/// window of the model.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Conversation {
+    pub version: u32,
+    pub id: String,
+    #[serde(default)]
+    pub persona: String,
+    #[serde(default)]
+    pub summary: Option<String>,
+    pub turns: Vec<Turn>,
+}
+
+impl Conversation {
+    fn new(chat_ptr: &str) -> Self {
+        Self {
+            version: CONVERSATION_VERSION,
+            id: chat_ptr.to_owned(),
+            persona: String::new(),
+            summary: None,
+            turns: vec![],
+        }
+    }
+
+    fn key(chat_ptr: &str) -> String {
+        format!("conv--v{}--{}", CONVERSATION_VERSION, chat_ptr)
+    }
+
+    /// Load the conversation of the context, which is empty for a new one.
+    pub fn load(chat_ptr: &str) -> Self {
+        store_flows::get(&Self::key(chat_ptr))
+            .and_then(|v| serde_json::from_value(v).ok())
+            .unwrap_or_else(|| Self::new(chat_ptr))
+    }
+
+    pub fn save(&self) -> anyhow::Result<()> {
+        store_flows::set(&Self::key(&self.id), serde_json::to_value(self)?, None);
+        Ok(())
+    }
+
+    pub fn has_images(&self) -> bool {
+        self.turns.iter().any(|turn| !turn.images.is_empty())
+    }
+
+    /// The system prompt with the summary of the dropped turns.
+    pub fn system_prompt(&self, prompt: &str) -> String {
+        match self.summary {
+            Some(ref summary) => format!(
+                "{}\n\nSummary of the earlier conversation:\n{}",
+                prompt, summary
+            ),
+            None => prompt.to_owned(),
+        }
+    }
+
+    /// The latest user turn with images, which is the only one whose images
+    /// are sent so the older ones aren't paid for on every turn.
+    fn image_turn(&self) -> Option<usize> {
+        self.turns
+            .iter()
+            .rposition(|turn| turn.role == Role::User && !turn.images.is_empty())
+    }
+
+    /// The images sent in requests, see `request_messages`.
+    pub fn request_images(&self) -> &[String] {
+        self.image_turn()
+            .map(|index| self.turns[index].images.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// Messages of a request, the system prompt always comes first. `images`
+    /// are the `data:` urls of `request_images`.
+    pub fn request_messages(&self, prompt: &str, images: &[String]) -> Vec<ChatMessage> {
+        let image_turn = self.image_turn();
+        let mut messages = vec![ChatMessage::system(self.system_prompt(prompt))];
+        messages.extend(self.turns.iter().enumerate().map(|(index, turn)| {
+            if Some(index) == image_turn {
+                turn.to_message(images)
+            } else {
+                turn.to_message(&[])
+            }
+        }));
+        messages
+    }
+
+    pub fn tokens(&self, prompt: &str) -> usize {
+        estimate_tokens(&self.system_prompt(prompt))
+            + self.turns.iter().map(|turn| turn.tokens).sum::<usize>()
+            + self.request_images().len() * IMAGE_TOKENS
+    }
+
+    /// Drop the oldest turns until the conversation fits in the context
+    /// window of `model` together with the system prompt and the answer, and
+    /// return them. The last turn is always kept, and the kept turns never
+    /// start with an answer.
+    pub fn fit(&mut self, prompt: &str, model: &str) -> Vec<Turn> {
+        let budget = settings::context_window(model).saturating_sub(ANSWER_TOKENS);
+        let mut tokens = self.tokens(prompt);
+        let mut dropped = 0;
+        while tokens > budget && dropped + 1 < self.turns.len() {
+            tokens -= self.turns[dropped].tokens;
+            dropped += 1;
+        }
+        while dropped > 0
+            && dropped + 1 < self.turns.len()
+            && self.turns[dropped].role == Role::Assistant
+        {
+            dropped += 1;
+        }
+        self.turns.drain(..dropped).collect()
+    }
+
+    /// The request summarizing `dropped` turns together with the current
+    /// summary, the oldest of them are left out if they don't fit in the
+    /// context window of `model`.
+    pub fn summary_request(&self, dropped: &[Turn], model: &str) -> Vec<ChatMessage> {
+        let budget = settings::context_window(model).saturating_sub(ANSWER_TOKENS);
+        let mut transcript = vec![];
+        if let Some(ref summary) = self.summary {
+            transcript.push(format!("summary of the earlier conversation: {}", summary));
+        }
+        let mut tokens = estimate_tokens(SUMMARY_PROMPT) + estimate_tokens(&transcript.concat());
+        let mut kept = vec![];
+        for turn in dropped.iter().rev() {
+            let line = turn.transcript();
+            tokens += estimate_tokens(&line);
+            if tokens > budget {
+                break;
+            }
+            kept.push(line);
+        }
+        transcript.extend(kept.into_iter().rev());
+        vec![
+            ChatMessage::system(SUMMARY_PROMPT),
+            ChatMessage::user(transcript.join("\n")),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conversation(turns: Vec<Turn>) -> Conversation {
+        Conversation {
+            turns,
+            ..Conversation::new("test")
+        }
+    }
+
+    #[test]
+    fn fit_keeps_everything_within_the_window() {
+        let mut conv = conversation(vec![
+            Turn::user("hi", vec![], 1),
+            Turn::assistant("hello", &[2], "gpt-3.5-turbo", 0),
+            Turn::user("how are you", vec![], 3),
+        ]);
+        assert!(conv.fit("prompt", "gpt-3.5-turbo").is_empty());
+        assert_eq!(conv.turns.len(), 3);
+    }
+
+    #[test]
+    fn fit_never_starts_with_an_answer() {
+        let mut conv = conversation(vec![
+            Turn::user(&"a".repeat(16000), vec![], 1),
+            Turn::assistant("short answer", &[2], "gpt-3.5-turbo", 0),
+            Turn::user("question", vec![], 3),
+            Turn::assistant("answer", &[4], "gpt-3.5-turbo", 0),
+            Turn::user("follow-up", vec![], 5),
+        ]);
+        let dropped = conv.fit("prompt", "gpt-3.5-turbo");
+        // the first turn alone is enough, but its answer can't be kept alone
+        assert_eq!(dropped.len(), 2);
+        assert_eq!(conv.turns.len(), 3);
+        assert_eq!(conv.turns[0].role, Role::User);
+        assert_eq!(conv.turns[0].message_id, Some(3));
+    }
+
+    #[test]
+    fn fit_keeps_the_last_turn() {
+        let mut conv = conversation(vec![
+            Turn::user("question", vec![], 1),
+            Turn::assistant(&"a".repeat(20000), &[2], "gpt-3.5-turbo", 0),
+        ]);
+        let dropped = conv.fit("prompt", "gpt-3.5-turbo");
+        assert_eq!(dropped.len(), 1);
+        assert_eq!(conv.turns.len(), 1);
+        assert_eq!(conv.turns[0].role, Role::Assistant);
+    }
+}
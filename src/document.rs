@@ -0,0 +1,202 @@
+use std::path::Path;
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+
+use crate::conversation::ANSWER_TOKENS;
+use crate::settings;
+use crate::tokens::estimate_tokens;
+
+/// Size of a chunk of a document, in tokens.
+const CHUNK_TOKENS: usize = 500;
+
+const TEXT_EXTENSIONS: [&str; 38] = [
+    "txt", "text", "log", "md", "markdown", "rst", "csv", "tsv", "json", "yaml", "yml", "toml",
+    "ini", "xml", "html", "htm", "css", "rs", "py", "js", "ts", "jsx", "tsx", "go", "c", "h",
+    "cpp", "hpp", "cc", "java", "kt", "swift", "rb", "php", "sh", "sql", "lua", "scala",
+];
+
+/// Text of a document, split into chunks of about [`CHUNK_TOKENS`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Document {
+    pub name: String,
+    pub chunks: Vec<String>,
+}
+
+impl Document {
+    /// Extract the text of a file, plain text, Markdown and source code are
+    /// read as UTF-8 while PDF files are parsed.
+    pub fn extract(name: &str, mime_type: Option<&str>, data: &[u8]) -> anyhow::Result<Self> {
+        let extension = Path::new(name)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase())
+            .unwrap_or_default();
+        let mime_type = mime_type.unwrap_or_default();
+
+        let text = if mime_type == "application/pdf" || extension == "pdf" {
+            match pdf_extract::extract_text_from_mem(data) {
+                Ok(text) => text,
+                Err(e) => bail!("failed to read the PDF file {}: {}", name, e),
+            }
+        } else if mime_type.starts_with("text/") || TEXT_EXTENSIONS.contains(&extension.as_str()) {
+            match String::from_utf8(data.to_vec()) {
+                Ok(text) => text,
+                Err(_) => bail!("{} isn't a text file in UTF-8", name),
+            }
+        } else {
+            bail!(
+                "{} isn't supported, only text, Markdown, source code and PDF files are",
+                name
+            )
+        };
+
+        if text.trim().is_empty() {
+            bail!("no text is found in {}", name);
+        }
+        // the rest of a longer document could never be sent to any model
+        let limit = max_context_budget();
+        let mut used = 0;
+        let chunks = split_chunks(&text, CHUNK_TOKENS)
+            .into_iter()
+            .take_while(|chunk| {
+                used += estimate_tokens(chunk);
+                used <= limit
+            })
+            .collect();
+        Ok(Self {
+            name: name.to_owned(),
+            chunks,
+        })
+    }
+
+    fn key(id: &str) -> String {
+        format!("doc--{}", id)
+    }
+
+    pub fn load(id: &str) -> Option<Self> {
+        store_flows::get(&Self::key(id)).and_then(|v| serde_json::from_value(v).ok())
+    }
+
+    pub fn save(&self, id: &str) -> anyhow::Result<()> {
+        log::info!("save document {}: {} chunks", id, self.chunks.len());
+        store_flows::set(&Self::key(id), serde_json::to_value(self)?, None);
+        Ok(())
+    }
+}
+
+/// Split text into chunks by lines, a line longer than a chunk is split by
+/// characters.
+fn split_chunks(text: &str, max_tokens: usize) -> Vec<String> {
+    let mut chunks = vec![];
+    let mut chunk = String::new();
+    for line in text.lines() {
+        if !chunk.is_empty() && estimate_tokens(&chunk) + estimate_tokens(line) > max_tokens {
+            chunks.push(std::mem::take(&mut chunk));
+        }
+        let mut line = line;
+        while estimate_tokens(line) > max_tokens {
+            let end = token_boundary(line, max_tokens);
+            chunks.push(line[..end].to_owned());
+            line = &line[end..];
+        }
+        chunk.push_str(line);
+        chunk.push('\n');
+    }
+    if !chunk.trim().is_empty() {
+        chunks.push(chunk);
+    }
+    chunks
+}
+
+/// The end of the longest prefix of `text` within `max_tokens`, which is at
+/// least one character.
+fn token_boundary(text: &str, max_tokens: usize) -> usize {
+    let (mut ascii, mut others) = (0, 0);
+    for (i, c) in text.char_indices() {
+        if c.is_ascii() {
+            ascii += 1;
+        } else {
+            others += 1;
+        }
+        if i > 0 && (ascii + 3) / 4 + others > max_tokens {
+            return i;
+        }
+    }
+    text.len()
+}
+
+fn keywords(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().count() > 2)
+        .map(|word| word.to_lowercase())
+        .collect()
+}
+
+/// Budget of the document context in the system prompt of `model`, which is
+/// half of the context window left by the answer and the rest of the prompt,
+/// the other half is for the conversation.
+pub fn context_budget(model: &str, prompt_tokens: usize) -> usize {
+    settings::context_window(model).saturating_sub(ANSWER_TOKENS + prompt_tokens) / 2
+}
+
+/// The largest budget of the document context among the language models.
+fn max_context_budget() -> usize {
+    settings::LANGUAGE_MODELS
+        .iter()
+        .map(|lm| context_budget(settings::api_model(lm), 0))
+        .max()
+        .unwrap_or_default()
+}
+
+/// Build the document context section of the system prompt. When the
+/// documents don't fit in `budget`, the chunks sharing the most words with the
+/// question are chosen, and kept in their original order.
+pub fn document_context(ids: &[String], question: &str, budget: usize) -> Option<String> {
+    let documents: Vec<Document> = ids.iter().filter_map(|id| Document::load(id)).collect();
+    if documents.is_empty() {
+        return None;
+    }
+
+    let keywords = keywords(question);
+    let mut chunks: Vec<(usize, usize, usize)> = vec![];
+    for (i, document) in documents.iter().enumerate() {
+        for (j, chunk) in document.chunks.iter().enumerate() {
+            let chunk_lower = chunk.to_lowercase();
+            let score = keywords
+                .iter()
+                .filter(|word| chunk_lower.contains(word.as_str()))
+                .count();
+            chunks.push((i, j, score));
+        }
+    }
+    // the beginning of a document is the most useful without a hint
+    chunks.sort_by(|a, b| b.2.cmp(&a.2).then(a.1.cmp(&b.1)));
+
+    let mut used = 0;
+    let mut selected = vec![];
+    for (i, j, _) in chunks {
+        let tokens = estimate_tokens(&documents[i].chunks[j]);
+        if used + tokens > budget {
+            continue;
+        }
+        used += tokens;
+        selected.push((i, j));
+    }
+    selected.sort();
+
+    let mut context = String::from(
+        "Document context:\nThe user has shared the documents below, answer with them when they are relevant.",
+    );
+    let mut last: Option<(usize, usize)> = None;
+    for (i, j) in selected {
+        if last.map(|(li, _)| li) != Some(i) {
+            context.push_str(&format!("\n\n### {}\n", documents[i].name));
+        } else if last.map(|(_, lj)| lj + 1) != Some(j) {
+            context.push_str("\n[...]\n");
+        }
+        context.push_str(&documents[i].chunks[j]);
+        last = Some((i, j));
+    }
+    Some(context)
+}
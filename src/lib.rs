@@ -1,6 +1,17 @@
+mod access;
+mod command;
+mod conversation;
+mod document;
 mod markdown;
+mod menu;
+mod multipart;
+mod openaiext;
+mod persona;
+mod settings;
 mod tgbot;
 mod tgext;
+mod tokens;
+mod usage;
 use flowsnet_platform_sdk::logger;
 
 use tg_flows::{listen_to_update, update_handler, Update};
@@ -1,16 +1,1172 @@
-use anyhow::bail;
 use nom::{
     branch::alt,
-    bytes::complete::{escaped_transform, tag, take_till, take_till1, take_until, take_while1},
-    character::{
-        complete::{anychar, char, multispace0, none_of, space1},
-        is_newline,
-    },
-    combinator::value,
-    multi::many1,
-    sequence::{delimited, preceded},
+    bytes::complete::{tag, take_until, take_while1, take_while_m_n},
+    character::complete::{char, one_of, space0, space1},
+    combinator::{eof, map, opt, value},
+    sequence::{delimited, preceded, terminated},
     IResult,
 };
+use serde::Serialize;
+
+/// Maximum length of a Telegram message text.
+pub const MESSAGE_LENGTH_LIMIT: usize = 4096;
+
+/// Bullets of the nested levels of lists.
+const BULLETS: [&str; 3] = ["•", "◦", "▪"];
+
+const RULE: &str = "——————";
+
+/// The formatting options of Telegram which Markdown is converted to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ParseMode {
+    MarkdownV2,
+    Html,
+    /// Plain text formatted by [`MessageEntity`]s, see [`render_entities`].
+    Entities,
+}
+
+impl ParseMode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "markdownv2" | "markdown" => Some(ParseMode::MarkdownV2),
+            "html" => Some(ParseMode::Html),
+            "entities" => Some(ParseMode::Entities),
+            _ => None,
+        }
+    }
+
+    /// The `parse_mode` of the Bot API, entities are sent without one.
+    pub fn name(&self) -> Option<&'static str> {
+        match self {
+            ParseMode::MarkdownV2 => Some("MarkdownV2"),
+            ParseMode::Html => Some("HTML"),
+            ParseMode::Entities => None,
+        }
+    }
+
+    /// Convert Markdown to the text of the parse mode, which never fails as
+    /// whatever isn't valid Markdown is kept as literal text. The text of
+    /// `Entities` is plain, without its entities.
+    pub fn render(&self, text: impl AsRef<str>) -> String {
+        match self.markup() {
+            Some(markup) => render_blocks(markup, &parse_document(text.as_ref()), 0, false),
+            None => render_entities(text).0,
+        }
+    }
+
+    /// Convert Markdown and split it into messages no longer than `limit`,
+    /// an inline span or a code block cut by a split is closed at the cut and
+    /// reopened in the next message.
+    pub fn split(&self, text: impl AsRef<str>, limit: usize) -> Vec<String> {
+        match self.markup() {
+            Some(markup) => split_blocks(markup, &parse_document(text.as_ref()), limit),
+            None => split_entities(text, limit)
+                .into_iter()
+                .map(|(text, _)| text)
+                .collect(),
+        }
+    }
+
+    /// The plain text of a converted text, used when Telegram rejects it.
+    pub fn unescape(&self, text: impl AsRef<str>) -> String {
+        match self {
+            ParseMode::MarkdownV2 => unescape_markdown(text),
+            ParseMode::Html => unescape_html(text),
+            ParseMode::Entities => text.as_ref().to_owned(),
+        }
+    }
+
+    fn markup(&self) -> Option<Markup> {
+        match self {
+            ParseMode::MarkdownV2 => Some(Markup::MarkdownV2),
+            ParseMode::Html => Some(Markup::Html),
+            ParseMode::Entities => None,
+        }
+    }
+}
+
+/// The markup of a parse mode, which the text is rendered in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Markup {
+    MarkdownV2,
+    Html,
+}
+
+/// A formatted part of a text sent with `entities` instead of a parse mode,
+/// its offset and length are in UTF-16 code units.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct MessageEntity {
+    #[serde(flatten)]
+    pub kind: EntityKind,
+    pub offset: usize,
+    pub length: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EntityKind {
+    Bold,
+    Italic,
+    Underline,
+    Strikethrough,
+    Spoiler,
+    Code,
+    Pre {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        language: Option<String>,
+    },
+    TextLink {
+        url: String,
+    },
+    Blockquote,
+}
+
+/// Convert Markdown to plain text and the entities formatting it, nothing has
+/// to be escaped in the text.
+pub fn render_entities(text: impl AsRef<str>) -> (String, Vec<MessageEntity>) {
+    let mut out = EntityText::default();
+    render_blocks_entities(&mut out, &parse_document(text.as_ref()), 0, false);
+    out.finish()
+}
+
+/// Convert Markdown to entities and split it into messages no longer than
+/// `limit`, preferably between blocks or lines. An entity cut by a split is
+/// in both messages.
+pub fn split_entities(text: impl AsRef<str>, limit: usize) -> Vec<(String, Vec<MessageEntity>)> {
+    let (text, entities) = render_entities(text);
+    let mut messages = vec![];
+    let (mut rest, mut offset) = (text.as_str(), 0);
+    while !rest.is_empty() {
+        let mut units = 0;
+        let fit = rest
+            .char_indices()
+            .find(|(_, c)| {
+                units += c.len_utf16();
+                units > limit
+            })
+            .map(|(i, _)| i);
+        let (end, skip) = match fit {
+            None => (rest.len(), 0),
+            Some(fit) => ["\n\n", "\n", " "]
+                .iter()
+                .find_map(|separator| {
+                    rest[..fit]
+                        .rfind(separator)
+                        .filter(|&i| i > 0)
+                        .map(|i| (i, separator.len()))
+                })
+                // at least a character is in a message
+                .unwrap_or((fit.max(rest.chars().next().map_or(0, char::len_utf8)), 0)),
+        };
+
+        let (message, tail) = rest.split_at(end);
+        let len = text_len(message);
+        let message_entities = entities
+            .iter()
+            .filter_map(|entity| {
+                let start = entity.offset.max(offset);
+                let end = (entity.offset + entity.length).min(offset + len);
+                (start < end).then(|| MessageEntity {
+                    kind: entity.kind.clone(),
+                    offset: start - offset,
+                    length: end - start,
+                })
+            })
+            .collect();
+        messages.push((message.to_owned(), message_entities));
+        // the separator at the split isn't sent
+        offset += len + skip;
+        rest = &tail[skip..];
+    }
+    messages
+}
+
+enum Inline {
+    Text(String),
+    Code(String),
+    Emphasis(Vec<Inline>),
+    Strong(Vec<Inline>),
+    Underline(Vec<Inline>),
+    Strikethrough(Vec<Inline>),
+    Spoiler(Vec<Inline>),
+    Link(Vec<Inline>, String),
+    LineBreak,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Align {
+    Left,
+    Center,
+    Right,
+}
+
+/// Blocks with whether each of them follows a blank line, which is kept in
+/// the output.
+type Blocks = Vec<(bool, Block)>;
+
+enum Block {
+    Heading(usize, Vec<Inline>),
+    Paragraph(Vec<Inline>),
+    /// The language, which may be empty, and the lines of a code block.
+    Code(String, String),
+    Quote(Blocks),
+    List(Vec<Item>),
+    /// Cells of the header and the rows, in plain text.
+    Table(Vec<Align>, Vec<Vec<String>>),
+    Rule,
+}
+
+/// An item of a list, the number of an item of an ordered list is kept as it
+/// is in the source.
+struct Item {
+    /// Whether the item follows a blank line.
+    blank: bool,
+    number: Option<u64>,
+    blocks: Blocks,
+}
+
+impl Item {
+    fn marker(&self, depth: usize) -> String {
+        match self.number {
+            Some(number) => format!("{}.", number),
+            None => BULLETS[depth % BULLETS.len()].to_owned(),
+        }
+    }
+}
+
+/// Styles of the enclosing inline elements, Telegram doesn't nest an entity
+/// in another of the same type.
+#[derive(Clone, Copy, Default)]
+struct Style {
+    bold: bool,
+    italic: bool,
+    underline: bool,
+    strikethrough: bool,
+    spoiler: bool,
+    link: bool,
+}
+
+/// Plain text with the entities formatting it, built like the text of a parse
+/// mode.
+#[derive(Default)]
+struct EntityText {
+    text: String,
+    /// Length of the text in UTF-16 code units, which offsets of entities
+    /// count.
+    len: usize,
+    entities: Vec<MessageEntity>,
+}
+
+impl EntityText {
+    fn push(&mut self, text: &str) {
+        self.text.push_str(text);
+        self.len += text_len(text);
+    }
+
+    /// Format the text from `start` to the end, unless it's empty.
+    fn mark(&mut self, start: usize, kind: EntityKind) {
+        if self.len > start {
+            self.entities.push(MessageEntity {
+                kind,
+                offset: start,
+                length: self.len - start,
+            });
+        }
+    }
+
+    /// Append another text with its lines after the first one indented, the
+    /// indentation of a line is out of the entities starting at the line.
+    fn append(&mut self, other: EntityText, indent: &str) {
+        let mut line_starts = vec![];
+        let mut units = 0;
+        for c in other.text.chars() {
+            units += c.len_utf16();
+            if c == '\n' {
+                line_starts.push(units);
+            }
+        }
+        let shift = text_len(indent);
+        let base = self.len;
+        for entity in other.entities {
+            let end = entity.offset + entity.length;
+            let start =
+                entity.offset + shift * line_starts.partition_point(|&line| line <= entity.offset);
+            let end = end + shift * line_starts.partition_point(|&line| line < end);
+            self.entities.push(MessageEntity {
+                offset: base + start,
+                length: end - start,
+                ..entity
+            });
+        }
+        self.push(&other.text.replace('\n', &format!("\n{}", indent)));
+    }
+
+    /// The text and its entities, an entity comes before the ones in it.
+    fn finish(mut self) -> (String, Vec<MessageEntity>) {
+        self.entities
+            .sort_by_key(|entity| (entity.offset, std::cmp::Reverse(entity.length)));
+        (self.text, self.entities)
+    }
+}
+
+impl Inline {
+    fn children(&self) -> &[Inline] {
+        match self {
+            Inline::Emphasis(children)
+            | Inline::Strong(children)
+            | Inline::Underline(children)
+            | Inline::Strikethrough(children)
+            | Inline::Spoiler(children)
+            | Inline::Link(children, _) => children,
+            Inline::Text(_) | Inline::Code(_) | Inline::LineBreak => &[],
+        }
+    }
+}
+
+impl Style {
+    /// The style inside an inline element, and the entity the element is
+    /// rendered as unless it's already in one of the same type.
+    fn enter(self, inline: &Inline) -> (Style, Option<EntityKind>) {
+        let mut style = self;
+        let (flag, kind) = match inline {
+            Inline::Emphasis(_) => (&mut style.italic, EntityKind::Italic),
+            Inline::Strong(_) => (&mut style.bold, EntityKind::Bold),
+            Inline::Underline(_) => (&mut style.underline, EntityKind::Underline),
+            Inline::Strikethrough(_) => (&mut style.strikethrough, EntityKind::Strikethrough),
+            Inline::Spoiler(_) => (&mut style.spoiler, EntityKind::Spoiler),
+            Inline::Link(_, url) => (&mut style.link, EntityKind::TextLink { url: url.clone() }),
+            Inline::Text(_) | Inline::Code(_) | Inline::LineBreak => return (self, None),
+        };
+        if *flag {
+            return (self, None);
+        }
+        *flag = true;
+        (style, Some(kind))
+    }
+}
+
+impl Block {
+    fn render(&self, mode: Markup) -> String {
+        self.render_nested(mode, 0, false)
+    }
+
+    /// Render the block in `depth` levels of lists, a quote in a quote is
+    /// merged into the outer one.
+    fn render_nested(&self, mode: Markup, depth: usize, quoted: bool) -> String {
+        match self {
+            Block::Heading(level, inlines) => {
+                let style = Style {
+                    bold: true,
+                    ..Default::default()
+                };
+                let title = render_inlines(mode, inlines, style);
+                match mode {
+                    Markup::MarkdownV2 => format!("`{}` *{}*", "#".repeat(*level), title),
+                    Markup::Html => {
+                        format!("<code>{}</code> <b>{}</b>", "#".repeat(*level), title)
+                    }
+                }
+            }
+            Block::Paragraph(inlines) => render_inlines(mode, inlines, Style::default()),
+            Block::Code(language, code) => render_pre(mode, language, code),
+            Block::Quote(blocks) => {
+                let content = render_blocks(mode, blocks, depth, true);
+                match mode {
+                    _ if quoted => content,
+                    Markup::MarkdownV2 => quote_lines(&content),
+                    Markup::Html => format!("<blockquote>{}</blockquote>", content),
+                }
+            }
+            Block::List(items) => {
+                let mut list = String::new();
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        list.push_str(separator(item.blank));
+                    }
+                    list.push_str(&render_item(mode, item, depth, quoted));
+                }
+                list
+            }
+            Block::Table(aligns, rows) => {
+                render_pre(mode, "", &format!("{}\n", render_table(aligns, rows)))
+            }
+            Block::Rule => RULE.to_owned(),
+        }
+    }
+
+    /// Render the block like [`Block::render_nested`] as plain text with
+    /// entities.
+    fn render_entities(&self, out: &mut EntityText, depth: usize, quoted: bool) {
+        let start = out.len;
+        match self {
+            Block::Heading(level, inlines) => {
+                out.push(&"#".repeat(*level));
+                out.mark(start, EntityKind::Code);
+                out.push(" ");
+                let start = out.len;
+                let style = Style {
+                    bold: true,
+                    ..Default::default()
+                };
+                render_inline_entities(out, inlines, style);
+                out.mark(start, EntityKind::Bold);
+            }
+            Block::Paragraph(inlines) => render_inline_entities(out, inlines, Style::default()),
+            Block::Code(language, code) => {
+                out.push(code.trim_end_matches('\n'));
+                let language = Some(language.clone()).filter(|language| !language.is_empty());
+                out.mark(start, EntityKind::Pre { language });
+            }
+            Block::Quote(blocks) => {
+                render_blocks_entities(out, blocks, depth, true);
+                if !quoted {
+                    out.mark(start, EntityKind::Blockquote);
+                }
+            }
+            Block::List(items) => {
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        out.push(separator(item.blank));
+                    }
+                    render_item_entities(out, item, depth, quoted);
+                }
+            }
+            Block::Table(aligns, rows) => {
+                out.push(&render_table(aligns, rows));
+                out.mark(start, EntityKind::Pre { language: None });
+            }
+            Block::Rule => out.push(RULE),
+        }
+    }
+
+    /// Whether the block has no content, e.g. an unclosed fence.
+    fn is_empty(&self) -> bool {
+        match self {
+            Block::Code(_, code) => code.trim().is_empty(),
+            Block::Table(_, rows) => rows.iter().flatten().all(|cell| cell.trim().is_empty()),
+            Block::Quote(blocks) => blocks.is_empty(),
+            _ => false,
+        }
+    }
+
+    /// Whether the block has to start at the beginning of a line, where the
+    /// indentation of a list item can't go.
+    fn is_verbatim(&self) -> bool {
+        matches!(self, Block::Code(..) | Block::Table(..) | Block::Quote(_))
+    }
+
+    /// Split the block into rendered pieces no longer than `limit`, cutting
+    /// only between lines of a code block, between words of a paragraph,
+    /// between items of a list or between lines of other blocks. An inline
+    /// span cut by a split is closed at the cut and reopened after it.
+    fn split(&self, mode: Markup, limit: usize, depth: usize, quoted: bool) -> Vec<String> {
+        let rendered = self.render_nested(mode, depth, quoted);
+        if text_len(&rendered) <= limit {
+            return vec![rendered];
+        }
+        match self {
+            Block::Code(language, code) => split_pre(mode, language, code, limit),
+            Block::Table(aligns, rows) => split_pre(
+                mode,
+                "",
+                &format!("{}\n", render_table(aligns, rows)),
+                limit,
+            ),
+            Block::Paragraph(inlines) => split_inlines(mode, inlines, limit),
+            Block::Heading(level, inlines) => {
+                let prefix = match mode {
+                    Markup::MarkdownV2 => format!("`{}` ", "#".repeat(*level)),
+                    Markup::Html => format!("<code>{}</code> ", "#".repeat(*level)),
+                };
+                let style = Style {
+                    bold: true,
+                    ..Default::default()
+                };
+                let limit = limit.saturating_sub(text_len(&prefix));
+                let mut pieces = entity_atoms(mode, inlines, style, Some(EntityKind::Bold), limit);
+                match pieces.first_mut() {
+                    Some(first) => first.insert_str(0, &prefix),
+                    None => pieces.push(prefix),
+                }
+                pieces
+            }
+            Block::Quote(blocks) => match mode {
+                _ if quoted => split_children(mode, blocks, limit, depth),
+                Markup::MarkdownV2 => split_fitting(
+                    limit,
+                    |inner| split_children(mode, blocks, inner, depth),
+                    |_, piece| quote_lines(piece.trim_start_matches('\n')),
+                ),
+                Markup::Html => {
+                    let (open, close) = ("<blockquote>", "</blockquote>");
+                    let inner = limit.saturating_sub(open.len() + close.len());
+                    split_children(mode, blocks, inner, depth)
+                        .into_iter()
+                        .map(|content| {
+                            format!("{}{}{}", open, content.trim_start_matches('\n'), close)
+                        })
+                        .collect()
+                }
+            },
+            Block::List(items) => pack(
+                mode,
+                items.iter().enumerate().flat_map(|(i, item)| {
+                    let mut parts = split_item(mode, item, limit.saturating_sub(2), depth, quoted);
+                    if i > 0 {
+                        parts[0].insert_str(0, separator(item.blank));
+                    }
+                    parts
+                }),
+                limit,
+            ),
+            Block::Rule => vec![rendered],
+        }
+    }
+}
+
+/// Blocks are separated by a blank line only if they are in the source.
+fn separator(blank: bool) -> &'static str {
+    if blank {
+        "\n\n"
+    } else {
+        "\n"
+    }
+}
+
+fn render_blocks(mode: Markup, blocks: &[(bool, Block)], depth: usize, quoted: bool) -> String {
+    let mut rendered = String::new();
+    for (i, (blank, block)) in blocks.iter().enumerate() {
+        if i > 0 {
+            rendered.push_str(separator(*blank));
+        }
+        rendered.push_str(&block.render_nested(mode, depth, quoted));
+    }
+    rendered
+}
+
+fn split_blocks(mode: Markup, blocks: &[(bool, Block)], limit: usize) -> Vec<String> {
+    let mut messages = vec![];
+    let mut current = String::new();
+    for (blank, block) in blocks.iter() {
+        // the pieces of a block follow each other without a separator
+        let mut separator = separator(*blank);
+        for piece in block.split(mode, limit, 0, false) {
+            if !current.is_empty()
+                && text_len(&current) + text_len(separator) + text_len(&piece) > limit
+            {
+                // a paragraph may be cut after a line break
+                messages.push(current.trim_end_matches('\n').to_owned());
+                current.clear();
+            }
+            if !current.is_empty() {
+                current.push_str(separator);
+            }
+            current.push_str(&piece);
+            separator = "";
+        }
+    }
+    // a text of only line breaks has no message
+    if !current.trim_end_matches('\n').is_empty() {
+        messages.push(current.trim_end_matches('\n').to_owned());
+    }
+    messages
+}
+
+/// Split the blocks of a quote like [`Block::split`], the content of each
+/// piece is quoted by the caller.
+fn split_children(
+    mode: Markup,
+    blocks: &[(bool, Block)],
+    limit: usize,
+    depth: usize,
+) -> Vec<String> {
+    let atoms = blocks.iter().enumerate().flat_map(|(i, (blank, block))| {
+        let mut pieces = block.split(mode, limit.saturating_sub(2), depth, true);
+        if let Some(first) = pieces.first_mut().filter(|_| i > 0) {
+            first.insert_str(0, separator(*blank));
+        }
+        pieces
+    });
+    pack(mode, atoms, limit)
+}
+
+/// Split an item of a list like [`render_item`], an item longer than `limit`
+/// is split by its blocks.
+fn split_item(mode: Markup, item: &Item, limit: usize, depth: usize, quoted: bool) -> Vec<String> {
+    let rendered = render_item(mode, item, depth, quoted);
+    if text_len(&rendered) <= limit {
+        return vec![rendered];
+    }
+    let marker = escape_text(mode, &item.marker(depth));
+    let indent = " ".repeat(item.marker(depth).chars().count() + 1);
+    // the marker is joined to the first piece
+    let limit = limit.saturating_sub(text_len(&marker));
+    let mut parts = vec![marker];
+    for (i, (blank, block)) in item.blocks.iter().enumerate() {
+        if block.is_verbatim() {
+            let mut pieces = block.split(mode, limit.saturating_sub(2), depth + 1, quoted);
+            pieces[0].insert_str(0, if i > 0 { separator(*blank) } else { "\n" });
+            parts.extend(pieces);
+            continue;
+        }
+        parts.extend(split_fitting(
+            limit.saturating_sub(2),
+            |inner| block.split(mode, inner, depth + 1, quoted),
+            |j, piece| match (i, j) {
+                (0, 0) => format!(" {}", indent_lines(piece, &indent).trim_start()),
+                (_, 0) => format!("{}{}", separator(*blank), indent_lines(piece, &indent)),
+                _ => indent_lines(piece, &indent),
+            },
+        ));
+    }
+    if parts.len() > 1 {
+        let first = parts.remove(1);
+        parts[0].push_str(&first);
+    }
+    parts
+}
+
+/// Split with `split` into pieces which are still no longer than `limit`
+/// after `wrap` adds to their lines, by shrinking the limit of `split`.
+fn split_fitting(
+    limit: usize,
+    split: impl Fn(usize) -> Vec<String>,
+    wrap: impl Fn(usize, &str) -> String,
+) -> Vec<String> {
+    let mut inner = limit;
+    loop {
+        let pieces: Vec<String> = split(inner)
+            .iter()
+            .enumerate()
+            .map(|(i, piece)| wrap(i, piece))
+            .collect();
+        let longest = pieces.iter().map(|piece| text_len(piece)).max();
+        let over = longest.unwrap_or_default().saturating_sub(limit);
+        if over == 0 || inner == 0 {
+            return pieces;
+        }
+        inner = inner.saturating_sub(over);
+    }
+}
+
+/// Quote the lines of MarkdownV2 with `>`, except the lines of a code block
+/// which can't be in a quote, so the quote is interrupted by it.
+fn quote_lines(content: &str) -> String {
+    let mut in_code = false;
+    content
+        .lines()
+        .map(|line| {
+            if in_code {
+                // the closing fence ends the last line of the code
+                in_code = !line.ends_with("```");
+                line.to_owned()
+            } else if line.starts_with("```") {
+                in_code = true;
+                line.to_owned()
+            } else {
+                format!(">{}", line)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Indent the lines of a rendered block in a list item, the lines of a quote
+/// of MarkdownV2 start with its marker.
+fn indent_lines(rendered: &str, indent: &str) -> String {
+    rendered
+        .lines()
+        .map(|line| {
+            if line.starts_with('>') {
+                line.to_owned()
+            } else {
+                format!("{}{}", indent, line)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Render an item of a list, the lines after the marker are indented under
+/// the text of the item.
+fn render_item(mode: Markup, item: &Item, depth: usize, quoted: bool) -> String {
+    let marker = item.marker(depth);
+    let indent = " ".repeat(marker.chars().count() + 1);
+    let mut out = escape_text(mode, &marker);
+    for (i, (blank, block)) in item.blocks.iter().enumerate() {
+        let rendered = block.render_nested(mode, depth + 1, quoted);
+        if block.is_verbatim() {
+            out.push_str(if i > 0 { separator(*blank) } else { "\n" });
+            out.push_str(&rendered);
+            continue;
+        }
+        let indented = indent_lines(&rendered, &indent);
+        if i == 0 {
+            out.push(' ');
+            out.push_str(indented.trim_start());
+        } else {
+            out.push_str(separator(*blank));
+            out.push_str(&indented);
+        }
+    }
+    out
+}
+
+/// Append rendered MarkdownV2, the underscores of italic and underline
+/// entities are separated from adjacent ones by `\r` as `___` is ambiguous.
+fn push_underscores(out: &mut String, rendered: &str) {
+    if needs_cr(out, rendered) {
+        out.push('\r');
+    }
+    out.push_str(rendered);
+}
+
+fn needs_cr(out: &str, rendered: &str) -> bool {
+    let escaped = |rest: &str| (rest.len() - rest.trim_end_matches('\\').len()) % 2 == 1;
+    rendered.starts_with('_') && matches!(out.strip_suffix('_'), Some(rest) if !escaped(rest))
+}
+
+/// Append rendered inline content between the markup of its entity.
+fn push_entity(mode: Markup, out: &mut String, open: &str, content: &str, close: &str) {
+    if mode == Markup::MarkdownV2 {
+        push_underscores(out, open);
+        push_underscores(out, content);
+        push_underscores(out, close);
+    } else {
+        out.push_str(open);
+        out.push_str(content);
+        out.push_str(close);
+    }
+}
+
+fn render_inlines(mode: Markup, inlines: &[Inline], style: Style) -> String {
+    let mut out = String::new();
+    for inline in inlines {
+        match inline {
+            Inline::Text(text) => {
+                out.push_str(&escape_text(mode, text));
+                continue;
+            }
+            Inline::Code(code) => {
+                match mode {
+                    Markup::MarkdownV2 => out.push_str(&format!("`{}`", escape_code(code))),
+                    Markup::Html => out.push_str(&format!("<code>{}</code>", escape_html(code))),
+                }
+                continue;
+            }
+            Inline::LineBreak => {
+                out.push('\n');
+                continue;
+            }
+            _ => {}
+        }
+        let (style, kind) = style.enter(inline);
+        let content = render_inlines(mode, inline.children(), style);
+        let (open, close) = kind
+            .map(|kind| entity_markup(mode, &kind))
+            .unwrap_or_default();
+        push_entity(mode, &mut out, &open, &content, &close);
+    }
+    out
+}
+
+/// Split a paragraph into rendered pieces no longer than `limit` between
+/// words, the entities cut by a split are closed and reopened.
+fn split_inlines(mode: Markup, inlines: &[Inline], limit: usize) -> Vec<String> {
+    let atoms = inlines
+        .iter()
+        .flat_map(|inline| inline_atoms(mode, inline, Style::default(), limit));
+    pack_inlines(mode, atoms, limit)
+}
+
+/// Rendered parts of an inline element no longer than `limit` unless a word
+/// is, an entity longer than `limit` is split into parts each in the markup
+/// of the entity.
+fn inline_atoms(mode: Markup, inline: &Inline, style: Style, limit: usize) -> Vec<String> {
+    let rendered = render_inlines(mode, std::slice::from_ref(inline), style);
+    if text_len(&rendered) <= limit {
+        return vec![rendered];
+    }
+    match inline {
+        Inline::Text(text) => text
+            .split_inclusive(char::is_whitespace)
+            .map(|word| escape_text(mode, word))
+            .collect(),
+        Inline::Code(code) => {
+            // the code is cut anywhere as it has no entities
+            let (open, close, escaped) = match mode {
+                Markup::MarkdownV2 => ("`", "`", escape_code(code)),
+                Markup::Html => ("<code>", "</code>", escape_html(code)),
+            };
+            let markup = text_len(open) + text_len(close);
+            let inner = limit.saturating_sub(markup);
+            let longest = match mode {
+                Markup::MarkdownV2 => escaped_units(&escaped).map(text_len).max(),
+                Markup::Html => html_units(&escaped).map(text_len).max(),
+            };
+            if inner < markup.max(longest.unwrap_or_default()) {
+                // the markup takes most of what an outer entity leaves
+                return inline_atoms(mode, &Inline::Text(code.clone()), style, limit);
+            }
+            pack(mode, std::iter::once(escaped), inner)
+                .into_iter()
+                .map(|content| format!("{}{}{}", open, content, close))
+                .collect()
+        }
+        _ => {
+            let (style, kind) = style.enter(inline);
+            entity_atoms(mode, inline.children(), style, kind, limit)
+        }
+    }
+}
+
+/// Split the content of an entity into parts no longer than `limit`, each of
+/// them in the markup of the entity unless the markup takes most of the
+/// limit, e.g. the url of a long link.
+fn entity_atoms(
+    mode: Markup,
+    children: &[Inline],
+    style: Style,
+    kind: Option<EntityKind>,
+    limit: usize,
+) -> Vec<String> {
+    let (open, close) = kind
+        .map(|kind| entity_markup(mode, &kind))
+        .unwrap_or_default();
+    // the markup may be separated from underscores by `\r`
+    let markup = text_len(&open) + text_len(&close);
+    let inner = limit.saturating_sub(markup + 2);
+    if inner < markup {
+        return children
+            .iter()
+            .flat_map(|child| inline_atoms(mode, child, style, limit))
+            .collect();
+    }
+    let atoms = children
+        .iter()
+        .flat_map(|child| inline_atoms(mode, child, style, inner));
+    pack_inlines(mode, atoms, inner)
+        .into_iter()
+        .map(|content| {
+            let mut part = String::new();
+            push_entity(mode, &mut part, &open, &content, &close);
+            part
+        })
+        .collect()
+}
+
+/// Concatenate rendered inline parts like [`pack`], adjacent underscores are
+/// separated as they are in [`render_inlines`].
+fn pack_inlines(mode: Markup, atoms: impl Iterator<Item = String>, limit: usize) -> Vec<String> {
+    let mut chunks = vec![];
+    let mut current = String::new();
+    for atom in atoms {
+        let cr = usize::from(mode == Markup::MarkdownV2 && needs_cr(&current, &atom));
+        if !current.is_empty() && text_len(&current) + cr + text_len(&atom) > limit {
+            chunks.push(std::mem::take(&mut current));
+        }
+        if text_len(&atom) > limit {
+            // only a word is longer, which is plain text
+            if !current.is_empty() {
+                chunks.push(std::mem::take(&mut current));
+            }
+            chunks.extend(pack(mode, std::iter::once(atom), limit));
+            current = chunks.pop().unwrap_or_default();
+            continue;
+        }
+        match mode {
+            Markup::MarkdownV2 => push_underscores(&mut current, &atom),
+            Markup::Html => current.push_str(&atom),
+        }
+    }
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+    chunks
+}
+
+fn render_blocks_entities(
+    out: &mut EntityText,
+    blocks: &[(bool, Block)],
+    depth: usize,
+    quoted: bool,
+) {
+    for (i, (blank, block)) in blocks.iter().enumerate() {
+        if i > 0 {
+            out.push(separator(*blank));
+        }
+        block.render_entities(out, depth, quoted);
+    }
+}
+
+/// Render an item of a list like [`render_item`] as plain text with entities.
+fn render_item_entities(out: &mut EntityText, item: &Item, depth: usize, quoted: bool) {
+    let marker = item.marker(depth);
+    let indent = " ".repeat(marker.chars().count() + 1);
+    out.push(&marker);
+    for (i, (blank, block)) in item.blocks.iter().enumerate() {
+        let mut rendered = EntityText::default();
+        block.render_entities(&mut rendered, depth + 1, quoted);
+        if block.is_verbatim() {
+            out.push(if i > 0 { separator(*blank) } else { "\n" });
+            out.append(rendered, "");
+            continue;
+        }
+        if i == 0 {
+            out.push(" ");
+        } else {
+            out.push(separator(*blank));
+            out.push(&indent);
+        }
+        out.append(rendered, &indent);
+    }
+}
+
+fn render_inline_entities(out: &mut EntityText, inlines: &[Inline], style: Style) {
+    for inline in inlines {
+        let start = out.len;
+        match inline {
+            Inline::Text(text) => out.push(text),
+            Inline::Code(code) => {
+                out.push(code);
+                out.mark(start, EntityKind::Code);
+            }
+            Inline::LineBreak => out.push("\n"),
+            _ => {
+                let (style, kind) = style.enter(inline);
+                render_inline_entities(out, inline.children(), style);
+                if let Some(kind) = kind {
+                    out.mark(start, kind);
+                }
+            }
+        }
+    }
+}
+
+/// The markup before and after the content of an entity.
+fn entity_markup(mode: Markup, kind: &EntityKind) -> (String, String) {
+    if let EntityKind::TextLink { url } = kind {
+        return match mode {
+            Markup::MarkdownV2 => ("[".to_owned(), format!("]({})", escaped_for_tg(url))),
+            Markup::Html => (
+                format!("<a href=\"{}\">", escape_html(url).replace('"', "&quot;")),
+                "</a>".to_owned(),
+            ),
+        };
+    }
+    let (markdown, html) = match kind {
+        EntityKind::Bold => (("*", "*"), ("<b>", "</b>")),
+        EntityKind::Italic => (("_", "_"), ("<i>", "</i>")),
+        EntityKind::Underline => (("__", "__"), ("<u>", "</u>")),
+        EntityKind::Strikethrough => (("~", "~"), ("<s>", "</s>")),
+        EntityKind::Spoiler => (("||", "||"), ("<tg-spoiler>", "</tg-spoiler>")),
+        _ => (("", ""), ("", "")),
+    };
+    let (open, close) = match mode {
+        Markup::MarkdownV2 => markdown,
+        Markup::Html => html,
+    };
+    (open.to_owned(), close.to_owned())
+}
+
+fn render_pre(mode: Markup, language: &str, code: &str) -> String {
+    match mode {
+        Markup::MarkdownV2 => format!("```{}\n{}```", language, escape_code(code)),
+        Markup::Html if language.is_empty() => {
+            format!("<pre>{}</pre>", escape_html(code.trim_end_matches('\n')))
+        }
+        Markup::Html => format!(
+            "<pre><code class=\"language-{}\">{}</code></pre>",
+            language,
+            escape_html(code.trim_end_matches('\n'))
+        ),
+    }
+}
+
+fn plain_text(inlines: &[Inline]) -> String {
+    inlines
+        .iter()
+        .map(|inline| match inline {
+            Inline::Text(text) | Inline::Code(text) => text.clone(),
+            Inline::Emphasis(children)
+            | Inline::Strong(children)
+            | Inline::Underline(children)
+            | Inline::Strikethrough(children)
+            | Inline::Spoiler(children) => plain_text(children),
+            // a code block has no links, so the url follows the text
+            Inline::Link(children, url) => match plain_text(children) {
+                text if text == *url => text,
+                text => format!("{} ({})", text, url),
+            },
+            Inline::LineBreak => " ".to_owned(),
+        })
+        .collect()
+}
+
+/// Columns of a table in a monospaced block are aligned by the width of
+/// their text, where CJK characters and emoji take two columns.
+fn display_width(text: &str) -> usize {
+    text.chars()
+        .map(|c| match c as u32 {
+            0x1100..=0x115F
+            | 0x2E80..=0xA4CF
+            | 0xAC00..=0xD7A3
+            | 0xF900..=0xFAFF
+            | 0xFE30..=0xFE4F
+            | 0xFF00..=0xFF60
+            | 0xFFE0..=0xFFE6
+            | 0x1F300..=0x1F64F
+            | 0x1F900..=0x1F9FF
+            | 0x20000..=0x3FFFD => 2,
+            _ => 1,
+        })
+        .sum()
+}
+
+/// Lay out a table in plain text with aligned columns, the first row is the
+/// header.
+fn render_table(aligns: &[Align], rows: &[Vec<String>]) -> String {
+    let widths: Vec<usize> = (0..aligns.len())
+        .map(|i| {
+            rows.iter()
+                .map(|row| display_width(&row[i]))
+                .max()
+                .unwrap_or_default()
+        })
+        .collect();
+    let render_row = |row: &Vec<String>| {
+        row.iter()
+            .zip(aligns.iter().zip(widths.iter()))
+            .map(|(cell, (align, width))| {
+                let padding = width - display_width(cell);
+                let left = match align {
+                    Align::Left => 0,
+                    Align::Center => padding / 2,
+                    Align::Right => padding,
+                };
+                format!("{}{}{}", " ".repeat(left), cell, " ".repeat(padding - left))
+            })
+            .collect::<Vec<_>>()
+            .join(" | ")
+            .trim_end()
+            .to_owned()
+    };
+
+    let mut lines = vec![];
+    for (i, row) in rows.iter().enumerate() {
+        lines.push(render_row(row));
+        if i == 0 {
+            lines.push(
+                widths
+                    .iter()
+                    .map(|width| "-".repeat(*width))
+                    .collect::<Vec<_>>()
+                    .join("-+-"),
+            );
+        }
+    }
+    lines.join("\n")
+}
+
+fn text_len(text: &str) -> usize {
+    text.encode_utf16().count()
+}
+
+/// Split the code of a pre block by lines into blocks no longer than `limit`,
+/// each of them is tagged with the language.
+fn split_pre(mode: Markup, language: &str, code: &str, limit: usize) -> Vec<String> {
+    let (escaped, overhead) = match mode {
+        Markup::MarkdownV2 => (escape_code(code), 7),
+        Markup::Html => (escape_html(code), 42),
+    };
+    let lines = escaped.split_inclusive('\n').map(String::from);
+    let limit = limit.saturating_sub(overhead + text_len(language));
+    let mut chunks = pack(mode, lines, limit);
+    if chunks.is_empty() {
+        chunks.push(String::new());
+    }
+    chunks
+        .into_iter()
+        .map(|code| match mode {
+            Markup::MarkdownV2 => format!("```{}\n{}```", language, code),
+            Markup::Html if language.is_empty() => {
+                format!("<pre>{}</pre>", code.trim_end_matches('\n'))
+            }
+            Markup::Html => format!(
+                "<pre><code class=\"language-{}\">{}</code></pre>",
+                language,
+                code.trim_end_matches('\n')
+            ),
+        })
+        .collect()
+}
+
+/// Greedily concatenate `atoms` into chunks no longer than `limit`, an atom
+/// longer than `limit` is hard split without breaking its escape sequences.
+fn pack(mode: Markup, atoms: impl Iterator<Item = String>, limit: usize) -> Vec<String> {
+    let mut chunks = vec![];
+    let mut current = String::new();
+    for atom in atoms {
+        if text_len(&current) + text_len(&atom) > limit && !current.is_empty() {
+            chunks.push(std::mem::take(&mut current));
+        }
+        if text_len(&atom) <= limit {
+            current.push_str(&atom);
+            continue;
+        }
+        let units: Vec<&str> = match mode {
+            Markup::MarkdownV2 => escaped_units(&atom).collect(),
+            Markup::Html => html_units(&atom).collect(),
+        };
+        for c in units {
+            if text_len(&current) + text_len(c) > limit && !current.is_empty() {
+                chunks.push(std::mem::take(&mut current));
+            }
+            current.push_str(c);
+        }
+    }
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+    chunks
+}
+
+/// Iterate the characters of an escaped text, keeping a backslash together
+/// with the character it escapes.
+fn escaped_units(text: &str) -> impl Iterator<Item = &str> {
+    let mut rest = text;
+    std::iter::from_fn(move || {
+        let mut chars = rest.char_indices();
+        let (_, c) = chars.next()?;
+        let end = if c == '\\' {
+            chars.nth(1)
+        } else {
+            chars.next()
+        }
+        .map(|(i, _)| i)
+        .unwrap_or(rest.len());
+        let (unit, tail) = rest.split_at(end);
+        rest = tail;
+        Some(unit)
+    })
+}
+
+/// Iterate the characters, tags and character references of HTML.
+fn html_units(text: &str) -> impl Iterator<Item = &str> {
+    let mut rest = text;
+    std::iter::from_fn(move || {
+        let c = rest.chars().next()?;
+        let end = match c {
+            '<' => rest.find('>').map(|i| i + 1),
+            '&' => rest.find(';').map(|i| i + 1),
+            _ => None,
+        }
+        .unwrap_or(c.len_utf8());
+        let (unit, tail) = rest.split_at(end);
+        rest = tail;
+        Some(unit)
+    })
+}
 
 fn is_special_char(c: char) -> bool {
     matches!(
@@ -36,6 +1192,26 @@ fn is_special_char(c: char) -> bool {
     )
 }
 
+fn escape_text(mode: Markup, text: &str) -> String {
+    match mode {
+        Markup::MarkdownV2 => escaped_for_tg(text),
+        Markup::Html => escape_html(text),
+    }
+}
+
+/// Escape the text of code and pre entities, in which only backticks and
+/// backslashes are special.
+fn escape_code(text: impl AsRef<str>) -> String {
+    let mut escaped = String::new();
+    for c in text.as_ref().chars() {
+        if c == '`' || c == '\\' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
 fn escaped_for_tg(text: impl AsRef<str>) -> String {
     let mut escaped_string = String::new();
     for c in text.as_ref().chars() {
@@ -48,119 +1224,1184 @@ fn escaped_for_tg(text: impl AsRef<str>) -> String {
     escaped_string
 }
 
-fn parse_escaped_chars(paren: char) -> impl Fn(&str) -> IResult<&str, String> {
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Width of the indentation of a line, a tab takes four columns.
+fn indent(line: &str) -> usize {
+    line.chars()
+        .take_while(|c| c.is_whitespace())
+        .map(|c| if c == '\t' { 4 } else { 1 })
+        .sum()
+}
+
+/// Remove up to `width` columns of indentation.
+fn strip_indent(line: &str, width: usize) -> &str {
+    let mut columns = 0;
+    for (i, c) in line.char_indices() {
+        if columns >= width || !(c == ' ' || c == '\t') {
+            return &line[i..];
+        }
+        columns += if c == '\t' { 4 } else { 1 };
+    }
+    ""
+}
+
+fn is_blank(line: &str) -> bool {
+    line.trim().is_empty()
+}
+
+/// An opening fence of a code block, followed by its info string.
+fn parse_fence(line: &str) -> IResult<&str, &str> {
+    let (info, fence) = preceded(
+        space0,
+        alt((
+            take_while_m_n(3, usize::MAX, |c| c == '`'),
+            take_while_m_n(3, usize::MAX, |c| c == '~'),
+        )),
+    )(line)?;
+    if fence.starts_with('`') && info.contains('`') {
+        // a code span on a single line
+        return Err(nom::Err::Error(nom::error::Error::new(
+            line,
+            nom::error::ErrorKind::Tag,
+        )));
+    }
+    Ok((info, fence))
+}
+
+fn is_closing_fence(line: &str, fence: &str) -> bool {
+    match parse_fence(line) {
+        Ok((info, closing)) => {
+            closing.starts_with(&fence[..1]) && closing.len() >= fence.len() && is_blank(info)
+        }
+        Err(_) => false,
+    }
+}
+
+/// The level of an ATX heading, followed by its title.
+fn parse_heading(line: &str) -> IResult<&str, usize> {
+    let (title, hashes) = preceded(space0, take_while_m_n(1, 6, |c| c == '#'))(line)?;
+    let (title, _) = alt((space1, eof))(title)?;
+    Ok((title, hashes.len()))
+}
+
+fn is_rule(line: &str) -> bool {
+    let marks: String = line.chars().filter(|c| !c.is_whitespace()).collect();
+    marks.len() >= 3
+        && ['-', '*', '_']
+            .iter()
+            .any(|&mark| marks.chars().all(|c| c == mark))
+}
+
+/// The marker of a list item, which is the number of an item of an ordered
+/// list or none of a bullet list, followed by the text of the item.
+fn parse_list_marker(line: &str) -> IResult<&str, Option<u64>> {
+    let (rest, number) = preceded(
+        space0,
+        alt((
+            value(None, one_of("-*+")),
+            map(
+                terminated(
+                    take_while_m_n(1, 9, |c: char| c.is_ascii_digit()),
+                    one_of(".)"),
+                ),
+                |digits: &str| digits.parse().ok(),
+            ),
+        )),
+    )(line)?;
+    let (rest, _) = alt((space1, eof))(rest)?;
+    Ok((rest, number))
+}
+
+fn strip_quote(line: &str) -> Option<&str> {
+    let result: IResult<&str, _> = preceded(space0, terminated(char('>'), opt(char(' '))))(line);
+    result.ok().map(|(rest, _)| rest)
+}
+
+/// Cells of a row of a table, split by the pipes out of code spans.
+fn split_row(line: &str) -> Vec<&str> {
+    let line = line.trim();
+    let line = line.strip_prefix('|').unwrap_or(line);
+    let line = match line.strip_suffix('|') {
+        Some(rest) if !rest.ends_with('\\') => rest,
+        _ => line,
+    };
+
+    let mut cells = vec![];
+    let (mut start, mut in_code, mut escaped) = (0, false, false);
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '`' => in_code = !in_code,
+            '|' if !in_code => {
+                cells.push(line[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    cells.push(line[start..].trim());
+    cells
+}
+
+/// The alignments of the columns in the delimiter row of a table.
+fn parse_table_delimiter(line: &str) -> Option<Vec<Align>> {
+    if !line.contains('-') {
+        return None;
+    }
+    split_row(line)
+        .into_iter()
+        .map(|cell| {
+            let dashes = cell.trim_start_matches(':').trim_end_matches(':');
+            if dashes.is_empty() || !dashes.chars().all(|c| c == '-') {
+                return None;
+            }
+            Some(match (cell.starts_with(':'), cell.ends_with(':')) {
+                (true, true) => Align::Center,
+                (false, true) => Align::Right,
+                _ => Align::Left,
+            })
+        })
+        .collect()
+}
+
+fn is_table_start(lines: &[&str]) -> bool {
+    lines.len() > 1
+        && lines[0].contains('|')
+        && parse_table_delimiter(lines[1])
+            .map(|aligns| aligns.len() == split_row(lines[0]).len())
+            .unwrap_or(false)
+}
+
+/// Whether the first line starts a block which interrupts a paragraph.
+fn starts_block(lines: &[&str]) -> bool {
+    let line = lines[0];
+    if indent(line) >= 4 {
+        return false;
+    }
+    parse_fence(line).is_ok()
+        || parse_heading(line).is_ok()
+        || is_rule(line)
+        || strip_quote(line).is_some()
+        || matches!(parse_list_marker(line), Ok((rest, number))
+            if !is_blank(rest) && number.unwrap_or(1) == 1)
+        || is_table_start(lines)
+}
+
+fn parse_blocks(lines: &[&str]) -> Blocks {
+    let mut blocks = vec![];
+    let mut i = 0;
+    while i < lines.len() {
+        if is_blank(lines[i]) {
+            i += 1;
+            continue;
+        }
+        let blank = i > 0 && is_blank(lines[i - 1]);
+        let (block, used) = parse_block(&lines[i..]);
+        // a block which would be rendered as nothing is kept as literal text
+        let block = match block {
+            block if block.is_empty() => {
+                Block::Paragraph(vec![Inline::Text(lines[i..i + used].join("\n"))])
+            }
+            block => block,
+        };
+        blocks.push((blank, block));
+        i += used;
+    }
+    blocks
+}
+
+/// Parse the block starting at the first line, which isn't blank, and return
+/// it with the number of lines it takes.
+fn parse_block(lines: &[&str]) -> (Block, usize) {
+    let line = lines[0];
+    if indent(line) >= 4 {
+        // an indented code block, which blank lines are in unless they end it
+        let used = lines
+            .iter()
+            .position(|line| !is_blank(line) && indent(line) < 4)
+            .unwrap_or(lines.len());
+        let used = lines[..used]
+            .iter()
+            .rposition(|line| !is_blank(line))
+            .map_or(1, |last| last + 1);
+        let code = lines[..used]
+            .iter()
+            .map(|line| format!("{}\n", strip_indent(line, 4)))
+            .collect();
+        return (Block::Code(String::new(), code), used);
+    }
+    if let Ok((_, fence)) = parse_fence(line) {
+        let end = lines[1..]
+            .iter()
+            .position(|line| is_closing_fence(line, fence))
+            .map(|i| i + 1);
+        // the language is the first word of the info string, the rest of it
+        // is kept as the first line of the code
+        let info = line.trim_start().trim_start_matches(&fence[..1]).trim();
+        let (language, rest) = info.split_once(char::is_whitespace).unwrap_or((info, ""));
+        let (language, rest) = if language
+            .chars()
+            .all(|c| c.is_alphanumeric() || "+#-_.".contains(c))
+        {
+            (language, rest.trim())
+        } else {
+            ("", info)
+        };
+        let code = std::iter::once(rest)
+            .filter(|rest| !rest.is_empty())
+            .chain(lines[1..end.unwrap_or(lines.len())].iter().copied())
+            .map(|line| format!("{}\n", line))
+            .collect();
+        // an unclosed code block lasts to the end
+        let used = end.map(|end| end + 1).unwrap_or(lines.len());
+        return (Block::Code(language.to_owned(), code), used);
+    }
+    if let Ok((title, level)) = parse_heading(line) {
+        let title = title.trim();
+        let trimmed = title.trim_end_matches('#');
+        let title = if trimmed.is_empty() || trimmed.ends_with(' ') {
+            trimmed.trim_end()
+        } else {
+            title
+        };
+        return (Block::Heading(level, parse_inlines(title)), 1);
+    }
+    if is_rule(line) {
+        return (Block::Rule, 1);
+    }
+    if strip_quote(line).is_some() {
+        return parse_quote(lines);
+    }
+    if parse_list_marker(line).is_ok() {
+        return parse_list(lines);
+    }
+    if is_table_start(lines) {
+        return parse_table(lines);
+    }
+    parse_paragraph(lines)
+}
+
+fn parse_paragraph(lines: &[&str]) -> (Block, usize) {
+    let mut used = 1;
+    while used < lines.len() && !is_blank(lines[used]) {
+        let line = lines[used].trim();
+        let setext = if line.chars().all(|c| c == '=') {
+            Some(1)
+        } else if line.chars().all(|c| c == '-') {
+            Some(2)
+        } else {
+            None
+        };
+        if let Some(level) = setext {
+            let title = join_lines(&lines[..used]);
+            return (Block::Heading(level, parse_inlines(&title)), used + 1);
+        }
+        if starts_block(&lines[used..]) {
+            break;
+        }
+        used += 1;
+    }
+    (
+        Block::Paragraph(parse_inlines(&join_lines(&lines[..used]))),
+        used,
+    )
+}
+
+/// Join the lines of a paragraph, without their indentation and trailing
+/// spaces of hard line breaks.
+fn join_lines(lines: &[&str]) -> String {
+    lines
+        .iter()
+        .map(|line| line.trim())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn parse_quote(lines: &[&str]) -> (Block, usize) {
+    let mut content = vec![];
+    let mut used = 0;
+    while used < lines.len() {
+        match strip_quote(lines[used]) {
+            Some(rest) => content.push(rest),
+            // lazy continuation of a paragraph in the quote
+            None if !is_blank(lines[used])
+                && content.last().map(|line| !is_blank(line)).unwrap_or(false)
+                && !starts_block(&lines[used..]) =>
+            {
+                content.push(lines[used])
+            }
+            None => break,
+        }
+        used += 1;
+    }
+    (Block::Quote(parse_blocks(&content)), used)
+}
+
+fn parse_list(lines: &[&str]) -> (Block, usize) {
+    let marker_indent = indent(lines[0]);
+    let ordered = parse_list_marker(lines[0])
+        .map(|(_, number)| number.is_some())
+        .unwrap_or_default();
+
+    let mut items = vec![];
+    let mut blank = false;
+    let mut used = 0;
+    while used < lines.len() {
+        let line = lines[used];
+        let (rest, number) = match parse_list_marker(line) {
+            Ok((rest, number))
+                if number.is_some() == ordered
+                    && indent(line) <= marker_indent
+                    && !is_rule(line) =>
+            {
+                (rest, number)
+            }
+            _ => break,
+        };
+        let content_indent = indent(line) + line.trim_start().len() - rest.len();
+
+        let mut content = vec![rest];
+        let mut end = used + 1;
+        while end < lines.len() {
+            let line = lines[end];
+            if is_blank(line) {
+                // blank lines are in the item only if it continues after them
+                match lines[end..].iter().position(|line| !is_blank(line)) {
+                    Some(next) if indent(lines[end + next]) > marker_indent => {
+                        content.extend(lines[end..end + next].iter());
+                        end += next;
+                        continue;
+                    }
+                    _ => break,
+                }
+            }
+            if indent(line) > marker_indent {
+                content.push(strip_indent(line, content_indent));
+            } else if !is_blank(lines[end - 1])
+                && parse_list_marker(line).is_err()
+                && !starts_block(&lines[end..])
+            {
+                // lazy continuation of a paragraph in the item
+                content.push(line.trim());
+            } else {
+                break;
+            }
+            end += 1;
+        }
+        items.push(Item {
+            blank,
+            number,
+            blocks: parse_blocks(&content),
+        });
+        used = end;
+
+        // blank lines between the items of a loose list
+        blank = false;
+        match lines[used..].iter().position(|line| !is_blank(line)) {
+            Some(next) if next > 0 && parse_list_marker(lines[used + next]).is_ok() => {
+                used += next;
+                blank = true;
+            }
+            _ => {}
+        }
+    }
+    (Block::List(items), used.max(1))
+}
+
+fn parse_table(lines: &[&str]) -> (Block, usize) {
+    let mut aligns = parse_table_delimiter(lines[1]).unwrap_or_default();
+    let mut rows = vec![lines[0]];
+    let mut used = 2;
+    while used < lines.len() && !is_blank(lines[used]) && lines[used].contains('|') {
+        rows.push(lines[used]);
+        used += 1;
+    }
+    let mut rows: Vec<Vec<String>> = rows
+        .into_iter()
+        .map(|row| {
+            split_row(row)
+                .into_iter()
+                .map(|cell| plain_text(&parse_inlines(cell)))
+                .collect()
+        })
+        .collect();
+    // the cells out of the columns of the header get columns of their own
+    let columns = rows.iter().map(Vec::len).max().unwrap_or_default();
+    aligns.resize(columns, Align::Left);
+    for row in rows.iter_mut() {
+        row.resize(aligns.len(), String::new());
+    }
+    (Block::Table(aligns, rows), used)
+}
+
+fn parse_code(input: &str) -> IResult<&str, Inline> {
+    let (input, ticks) = take_while1(|c| c == '`')(input)?;
+    let (input, code) = take_until(ticks)(input)?;
+    let (input, _) = tag(ticks)(input)?;
+    let code = code.replace('\n', " ");
+    // a code span of only spaces, which Telegram drops, is literal text
+    if code.trim().is_empty() {
+        return Err(nom::Err::Error(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::Verify,
+        )));
+    }
+    let code = match code.strip_prefix(' ').and_then(|c| c.strip_suffix(' ')) {
+        Some(stripped) if !stripped.trim().is_empty() => stripped.to_owned(),
+        _ => code,
+    };
+    Ok((input, Inline::Code(code)))
+}
+
+/// Take the text up to the bracket closing an opened one, which is skipped.
+fn parse_balanced(open: char, close: char) -> impl Fn(&str) -> IResult<&str, &str> {
     move |input: &str| {
-        escaped_transform(
-            take_till1(|c| c == paren || c == '\\' || is_newline(c as u8)),
-            '\\',
-            alt((
-                value("_", tag("\\_")),
-                value("*", tag("\\*")),
-                value("[", tag("\\[")),
-                value("]", tag("\\]")),
-                value("(", tag("\\(")),
-                value(")", tag("\\)")),
-                value("~", tag("\\~")),
-                value("`", tag("\\`")),
-                value(">", tag("\\>")),
-                value("#", tag("\\#")),
-                value("+", tag("\\+")),
-                value("-", tag("\\-")),
-                value("=", tag("\\=")),
-                value("|", tag("\\|")),
-                value("{", tag("\\{")),
-                value("}", tag("\\}")),
-                value(".", tag("\\.")),
-                value("!", tag("\\!")),
-                value("\\", tag("\\\\")),
-            )),
-        )(input)
-    }
-}
-
-fn parse_emphasize(input: &str) -> IResult<&str, String> {
-    let (input, content) = alt((
-        delimited(tag("__"), parse_escaped_chars('_'), tag("__")),
-        delimited(char('_'), parse_escaped_chars('_'), char('_')),
-    ))(input)?;
-    Ok((input, format!("_{}_", escaped_for_tg(content))))
-}
-
-fn parse_bold(input: &str) -> IResult<&str, String> {
-    let (input, content) = alt((
-        delimited(tag("**"), parse_escaped_chars('*'), tag("**")),
-        delimited(char('*'), parse_escaped_chars('*'), char('*')),
-    ))(input)?;
-    Ok((input, format!("*{}*", escaped_for_tg(content))))
-}
-
-fn parse_code(input: &str) -> IResult<&str, String> {
-    let (input, content) = delimited(char('`'), parse_escaped_chars('`'), char('`'))(input)?;
-    Ok((input, format!("`{}`", escaped_for_tg(content))))
-}
-
-fn parse_link(input: &str) -> IResult<&str, String> {
-    let (input, text) = delimited(char('['), parse_escaped_chars(']'), char(']'))(input)?;
-    let (input, link) = delimited(char('('), parse_escaped_chars(')'), char(')'))(input)?;
-    Ok((
-        input,
-        format!("[{}]({})", escaped_for_tg(text), escaped_for_tg(link)),
-    ))
+        let mut depth = 0;
+        let mut escaped = false;
+        for (i, c) in input.char_indices() {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '\n' if open == '(' => break,
+                c if c == open => depth += 1,
+                c if c == close && depth == 0 => return Ok((&input[i + 1..], &input[..i])),
+                c if c == close => depth -= 1,
+                _ => {}
+            }
+        }
+        Err(nom::Err::Error(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::TakeUntil,
+        )))
+    }
 }
 
-fn parse_plaintext(input: &str) -> IResult<&str, String> {
-    let (input, content) = take_till1(|c| is_special_char(c) || is_newline(c as u8))(input)?;
-    Ok((input, escaped_for_tg(content)))
+fn has_scheme(url: &str) -> bool {
+    match url.split_once("://") {
+        Some((scheme, _)) => {
+            !scheme.is_empty()
+                && scheme
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+        }
+        None => false,
+    }
 }
 
-fn parse_special_chars(input: &str) -> IResult<&str, String> {
-    let (input, content) = take_while1(is_special_char)(input)?;
-    Ok((input, escaped_for_tg(content)))
+/// A link, or an image which is linked with its alt text.
+fn parse_link(input: &str) -> IResult<&str, Inline> {
+    let (input, _) = opt(char('!'))(input)?;
+    let (input, text) = preceded(char('['), parse_balanced('[', ']'))(input)?;
+    let (rest, destination) = preceded(char('('), parse_balanced('(', ')'))(input)?;
+    let url = destination.trim();
+    let url = url
+        .strip_prefix('<')
+        .and_then(|url| url.strip_suffix('>'))
+        .unwrap_or(url);
+    // a link with a title, which Telegram has no place for, or to a relative
+    // url, which Telegram can't open, is literal text
+    if !has_scheme(url) || url.contains(char::is_whitespace) {
+        return Err(nom::Err::Error(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::Verify,
+        )));
+    }
+    let text = match parse_inlines(text) {
+        text if text.is_empty() => vec![Inline::Text(url.to_owned())],
+        text => text,
+    };
+    Ok((rest, Inline::Link(text, url.to_owned())))
 }
 
-fn parse_header(input: &str) -> IResult<&str, String> {
-    let (input, htag) = many1(char('#'))(input)?;
-    let (input, _) = space1(input)?;
-    let (input, title) = parse_plaintext(input)?;
+fn parse_autolink(input: &str) -> IResult<&str, Inline> {
+    let (input, url) = preceded(
+        char('<'),
+        terminated(
+            take_while1(|c: char| c != '>' && c != '<' && !c.is_whitespace()),
+            char('>'),
+        ),
+    )(input)?;
+    let link = if has_scheme(url) {
+        url.to_owned()
+    } else if url.contains('@') && !url.contains(':') {
+        format!("mailto:{}", url)
+    } else {
+        return Err(nom::Err::Error(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::Verify,
+        )));
+    };
     Ok((
         input,
-        format!(
-            "`{}` *{}*",
-            escaped_for_tg(htag.into_iter().collect::<String>()),
-            title
-        ),
+        Inline::Link(vec![Inline::Text(url.to_owned())], link),
     ))
 }
 
-fn parse_paragraph(input: &str) -> IResult<&str, String> {
-    let (input, components) = many1(alt((
-        parse_emphasize,
-        parse_bold,
-        parse_code,
-        parse_link,
-        parse_plaintext,
-        parse_special_chars,
-    )))(input)?;
-    Ok((input, components.join("")))
+/// Underline, which Markdown has no syntax for, in an HTML tag.
+fn parse_underline(input: &str) -> IResult<&str, Inline> {
+    let (rest, content) = delimited(tag("<u>"), take_until("</u>"), tag("</u>"))(input)?;
+    if content.trim().is_empty() {
+        return Err(nom::Err::Error(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::Verify,
+        )));
+    }
+    Ok((rest, Inline::Underline(parse_inlines(content))))
+}
+
+/// A spoiler in `||`, the syntax of Telegram and Discord.
+fn parse_spoiler(input: &str) -> IResult<&str, Inline> {
+    let (rest, content) = delimited(tag("||"), take_until("||"), tag("||"))(input)?;
+    if content.trim().is_empty() || content.starts_with(char::is_whitespace) {
+        return Err(nom::Err::Error(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::Verify,
+        )));
+    }
+    Ok((rest, Inline::Spoiler(parse_inlines(content))))
 }
 
-fn parse_codeblock(input: &str) -> IResult<&str, String> {
-    let (input, content) = delimited(tag("```"), take_until("```"), tag("```"))(input)?;
-    Ok((input, format!("```{}```", escaped_for_tg(content))))
+/// Whether a run of delimiters between `prev` and `next` can open and close
+/// an emphasis, an underscore inside a word is a literal one.
+fn flanking(delimiter: char, prev: Option<char>, next: Option<char>) -> (bool, bool) {
+    let in_word =
+        |c: Option<char>| delimiter == '_' && c.map(char::is_alphanumeric).unwrap_or(false);
+    let left = next.map(|c| !c.is_whitespace()).unwrap_or(false) && !in_word(prev);
+    let right = prev.map(|c| !c.is_whitespace()).unwrap_or(false) && !in_word(next);
+    (left, right)
 }
 
-pub fn parse_markdown(input: &str) -> IResult<&str, String> {
-    let (input, lines) = many1(preceded(
-        multispace0,
-        alt((parse_codeblock, parse_header, parse_paragraph)),
-    ))(input)?;
-    Ok((input, lines.join("\n\n")))
+/// Find the run of `delimiter` closing an emphasis opened by `len` of them,
+/// the emphasis opened in between are closed first.
+fn find_closer(text: &str, delimiter: char, len: usize) -> Option<usize> {
+    let mut opened: Vec<usize> = vec![];
+    let mut prev = None;
+    let mut i = 0;
+    while let Some(c) = text[i..].chars().next() {
+        if c == '\\' {
+            let skipped = text[i..].chars().take(2).map(char::len_utf8).sum::<usize>();
+            prev = text[i..i + skipped].chars().last();
+            i += skipped;
+            continue;
+        }
+        if c == '`' {
+            if let Ok((rest, _)) = parse_code(&text[i..]) {
+                i = text.len() - rest.len();
+                prev = Some('`');
+                continue;
+            }
+        }
+        if c != delimiter {
+            prev = Some(c);
+            i += c.len_utf8();
+            continue;
+        }
+
+        let run = text[i..].chars().take_while(|&c| c == delimiter).count();
+        let next = text[i + run..].chars().next();
+        let (left, right) = flanking(delimiter, prev, next);
+        if right {
+            let mut rest = run;
+            while rest > 0 {
+                match opened.last_mut() {
+                    Some(open) if *open <= rest => {
+                        rest -= *open;
+                        opened.pop();
+                    }
+                    Some(open) => {
+                        *open -= rest;
+                        rest = 0;
+                    }
+                    None => break,
+                }
+            }
+            if opened.is_empty() && rest >= len {
+                return Some(i + run - rest);
+            }
+            if rest == run && left {
+                opened.push(run);
+            }
+        } else if left {
+            opened.push(run);
+        }
+        prev = Some(delimiter);
+        i += run;
+    }
+    None
 }
 
-pub fn escape_markdown(text: impl AsRef<str>) -> anyhow::Result<String> {
-    if let Ok((_, content)) = parse_markdown(text.as_ref()) {
-        Ok(content)
-    } else {
-        bail!("unable to correctly escape markdown")
+/// An emphasis, strong emphasis or strikethrough opened by the run of
+/// delimiters at the start of `input`.
+fn parse_emphasis(input: &str, prev: Option<char>) -> Option<(&str, Inline)> {
+    let delimiter = input.chars().next()?;
+    let run = input.chars().take_while(|&c| c == delimiter).count();
+    if run > 3 || (delimiter == '~' && run != 2) {
+        return None;
+    }
+    let after = &input[run..];
+    let (left, _) = flanking(delimiter, prev, after.chars().next());
+    if !left {
+        return None;
+    }
+    let end = find_closer(after, delimiter, run)?;
+    if end == 0 {
+        return None;
+    }
+    let children = parse_inlines(&after[..end]);
+    let inline = match (delimiter, run) {
+        ('~', _) => Inline::Strikethrough(children),
+        (_, 1) => Inline::Emphasis(children),
+        (_, 2) => Inline::Strong(children),
+        _ => Inline::Strong(vec![Inline::Emphasis(children)]),
+    };
+    Some((&after[end + run..], inline))
+}
+
+fn parse_inlines(text: &str) -> Vec<Inline> {
+    let mut inlines = vec![];
+    let mut plain = String::new();
+    let mut prev = None;
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        let parsed = match c {
+            '\\' => {
+                let mut chars = rest.chars();
+                chars.next();
+                match chars.next() {
+                    Some('\n') => Some((chars.as_str(), Inline::LineBreak)),
+                    Some(c) if c.is_ascii_punctuation() => {
+                        Some((chars.as_str(), Inline::Text(c.to_string())))
+                    }
+                    _ => None,
+                }
+            }
+            '\n' => Some((&rest[1..], Inline::LineBreak)),
+            '`' => parse_code(rest).ok(),
+            '[' | '!' => parse_link(rest).ok(),
+            '<' => parse_autolink(rest).or_else(|_| parse_underline(rest)).ok(),
+            '|' => parse_spoiler(rest).ok(),
+            '*' | '_' | '~' => parse_emphasis(rest, prev),
+            _ => None,
+        };
+
+        let tail = match parsed {
+            Some((tail, Inline::Text(text))) => {
+                plain.push_str(&text);
+                tail
+            }
+            Some((tail, inline)) => {
+                if !plain.is_empty() {
+                    inlines.push(Inline::Text(std::mem::take(&mut plain)));
+                }
+                inlines.push(inline);
+                tail
+            }
+            None => {
+                // a run of delimiters which doesn't open anything is literal
+                let end = if matches!(c, '`' | '*' | '_' | '~' | '|') {
+                    rest.find(|d| d != c).unwrap_or(rest.len())
+                } else {
+                    rest[c.len_utf8()..]
+                        .find(|c| "\\\n`[!<|*_~".contains(c))
+                        .map(|i| i + c.len_utf8())
+                        .unwrap_or(rest.len())
+                };
+                plain.push_str(&rest[..end]);
+                &rest[end..]
+            }
+        };
+        prev = rest[..rest.len() - tail.len()].chars().last();
+        rest = tail;
+    }
+    if !plain.is_empty() {
+        inlines.push(Inline::Text(plain));
+    }
+    inlines
+}
+
+/// Parse Markdown, a text of only blank lines is kept as it is.
+fn parse_document(text: &str) -> Blocks {
+    let lines: Vec<&str> = text.lines().collect();
+    match parse_blocks(&lines) {
+        blocks if blocks.is_empty() && !text.is_empty() => {
+            vec![(false, Block::Paragraph(vec![Inline::Text(text.to_owned())]))]
+        }
+        blocks => blocks,
+    }
+}
+
+/// Remove the escaping added for MarkdownV2.
+fn unescape_markdown(text: impl AsRef<str>) -> String {
+    escaped_units(text.as_ref())
+        .map(|unit| unit.strip_prefix('\\').unwrap_or(unit))
+        .collect()
+}
+
+/// Remove the tags of HTML and decode its character references.
+fn unescape_html(text: impl AsRef<str>) -> String {
+    html_units(text.as_ref())
+        .filter(|unit| !(unit.starts_with('<') && unit.ends_with('>')))
+        .map(|unit| match unit {
+            "&amp;" => "&",
+            "&lt;" => "<",
+            "&gt;" => ">",
+            "&quot;" => "\"",
+            unit => unit,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entity(kind: EntityKind, offset: usize, length: usize) -> MessageEntity {
+        MessageEntity {
+            kind,
+            offset,
+            length,
+        }
+    }
+
+    #[test]
+    fn entities_offsets_in_utf16() {
+        let (text, entities) = render_entities("**太字** 😀 [link](https://a.b) `コード`");
+        assert_eq!(text, "太字 😀 link コード");
+        assert_eq!(
+            entities,
+            vec![
+                entity(EntityKind::Bold, 0, 2),
+                entity(
+                    EntityKind::TextLink {
+                        url: "https://a.b".to_owned()
+                    },
+                    6,
+                    4
+                ),
+                entity(EntityKind::Code, 11, 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn entities_of_emoji_span() {
+        let (text, entities) = render_entities("a *😀b* c");
+        assert_eq!(text, "a 😀b c");
+        assert_eq!(entities, vec![entity(EntityKind::Italic, 2, 3)]);
+    }
+
+    #[test]
+    fn split_entities_between_lines() {
+        let parts = split_entities("😀😀 **bold**\n\n日本語の文 *斜体*", 8);
+        assert_eq!(
+            parts,
+            vec![
+                ("😀😀".to_owned(), vec![]),
+                ("bold".to_owned(), vec![entity(EntityKind::Bold, 0, 4)]),
+                (
+                    "日本語の文 斜体".to_owned(),
+                    vec![entity(EntityKind::Italic, 6, 2)]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn split_entities_cuts_entity_into_both_parts() {
+        let parts = split_entities("**ああああ😀😀**", 6);
+        assert_eq!(
+            parts,
+            vec![
+                (
+                    "ああああ😀".to_owned(),
+                    vec![entity(EntityKind::Bold, 0, 6)]
+                ),
+                ("😀".to_owned(), vec![entity(EntityKind::Bold, 0, 2)]),
+            ]
+        );
+    }
+
+    #[test]
+    fn split_long_link_into_links() {
+        let text = format!("[{}](https://a.b)", "x".repeat(5000));
+        for (mode, open, close) in [
+            (ParseMode::MarkdownV2, "[", "](https://a\\.b)"),
+            (ParseMode::Html, "<a href=\"https://a.b\">", "</a>"),
+        ] {
+            let parts = mode.split(&text, MESSAGE_LENGTH_LIMIT);
+            assert_eq!(parts.len(), 2);
+            for part in &parts {
+                assert!(text_len(part) <= MESSAGE_LENGTH_LIMIT);
+                assert!(part.starts_with(open) && part.ends_with(close), "{}", part);
+            }
+            let plain: String = parts.iter().map(|part| mode.unescape(part)).collect();
+            assert_eq!(plain.matches('x').count(), 5000);
+        }
+    }
+
+    #[test]
+    fn split_long_bold_into_bold_parts() {
+        let words = vec!["word"; 5000].join(" ");
+        let text = format!("a **{}** b", words);
+        for (mode, open, close) in [
+            (ParseMode::MarkdownV2, "*", "*"),
+            (ParseMode::Html, "<b>", "</b>"),
+        ] {
+            let parts = mode.split(&text, MESSAGE_LENGTH_LIMIT);
+            assert!(parts.len() > 5);
+            for (i, part) in parts.iter().enumerate() {
+                assert!(text_len(part) <= MESSAGE_LENGTH_LIMIT);
+                let part = part.strip_prefix("a ").filter(|_| i == 0).unwrap_or(part);
+                let last = i == parts.len() - 1;
+                let part = part.strip_suffix(" b").filter(|_| last).unwrap_or(part);
+                assert!(part.starts_with(open) && part.ends_with(close), "{}", part);
+                let content = &part[open.len()..part.len() - close.len()];
+                assert!(!content.contains(open) && !content.contains(close));
+            }
+            let plain: String = parts.iter().map(|part| mode.unescape(part)).collect();
+            assert_eq!(plain.split_whitespace().count(), 5002);
+        }
+    }
+
+    #[test]
+    fn split_nested_entities_reopens_both() {
+        let text = format!("**bold _{}_**", vec!["it"; 100].join(" "));
+        let parts = ParseMode::MarkdownV2.split(&text, 100);
+        assert!(parts.len() > 2);
+        for part in &parts[1..] {
+            assert!(part.starts_with("*_") && part.ends_with("_*"), "{}", part);
+        }
+        let parts = ParseMode::Html.split(&text, 100);
+        for part in &parts[1..] {
+            assert!(
+                part.starts_with("<b><i>") && part.ends_with("</i></b>"),
+                "{}",
+                part
+            );
+        }
+    }
+
+    #[test]
+    fn split_long_inline_code_into_code_spans() {
+        let text = format!("`{}`", "a__\\\\".repeat(100));
+        for (mode, open, close) in [
+            (ParseMode::MarkdownV2, "`", "`"),
+            (ParseMode::Html, "<code>", "</code>"),
+        ] {
+            for part in mode.split(&text, 50) {
+                assert!(text_len(&part) <= 50);
+                assert!(part.starts_with(open) && part.ends_with(close), "{}", part);
+                assert!(!part.contains('\r'));
+            }
+        }
+    }
+
+    #[test]
+    fn split_long_list_item_by_words() {
+        let text = format!("- {}\n- b", vec!["item"; 100].join(" "));
+        let parts = ParseMode::MarkdownV2.split(&text, 100);
+        assert!(parts[0].starts_with("• item"));
+        assert!(parts.iter().all(|part| text_len(part) <= 100));
+        assert!(parts.last().unwrap().ends_with("• b"));
+    }
+
+    #[test]
+    fn html_escapes_link_url() {
+        assert_eq!(
+            ParseMode::Html.render("[a <b>](https://a.b/?x=1&y=\"2\")"),
+            "<a href=\"https://a.b/?x=1&amp;y=&quot;2&quot;\">a &lt;b&gt;</a>"
+        );
+    }
+
+    #[test]
+    fn html_code_block_language_class() {
+        assert_eq!(
+            ParseMode::Html.render("```c++\na < b && c\n```"),
+            "<pre><code class=\"language-c++\">a &lt; b &amp;&amp; c</code></pre>"
+        );
+        // an info string which isn't a language is kept as code
+        assert_eq!(
+            ParseMode::Html.render("```a\"b<\nx\n```"),
+            "<pre>a\"b&lt;\nx</pre>"
+        );
+        let code = format!("```rust\n{}```", "let a = 1;\n".repeat(100));
+        for part in ParseMode::Html.split(&code, 200) {
+            assert!(part.starts_with("<pre><code class=\"language-rust\">let a"));
+            assert!(part.ends_with("</code></pre>") && text_len(&part) <= 200);
+        }
+    }
+
+    #[test]
+    fn html_spoiler() {
+        assert_eq!(
+            ParseMode::Html.render("a ||secret <x>|| b"),
+            "a <tg-spoiler>secret &lt;x&gt;</tg-spoiler> b"
+        );
+    }
+
+    #[test]
+    fn html_split_long_quote_into_quotes() {
+        let text = format!(
+            "> {}\n>\n> **{}**",
+            vec!["q"; 100].join(" "),
+            vec!["b"; 100].join(" ")
+        );
+        let parts = ParseMode::Html.split(&text, 100);
+        assert!(parts.len() > 4);
+        for part in &parts {
+            assert!(text_len(part) <= 100);
+            assert!(part.starts_with("<blockquote>") && part.ends_with("</blockquote>"));
+            assert_nested_tags(part);
+        }
+    }
+
+    /// Assert that the tags of an HTML part are closed in the reverse order
+    /// they are opened.
+    fn assert_nested_tags(part: &str) {
+        let mut open = vec![];
+        for tag in html_units(part).filter(|unit| unit.starts_with('<')) {
+            let name = tag
+                .trim_matches(|c| c == '<' || c == '>' || c == '/')
+                .split(' ')
+                .next()
+                .unwrap();
+            if tag.starts_with("</") {
+                assert_eq!(open.pop(), Some(name), "{}", part);
+            } else {
+                open.push(name);
+            }
+        }
+        assert!(open.is_empty(), "{}", part);
+    }
+
+    #[test]
+    fn html_split_code_in_entity_keeps_tags_nested() {
+        for text in [
+            "||`code span here`||",
+            "[`code span here`](http://x.y)",
+            "**a ||`some code` and `more code`|| b**",
+        ] {
+            for limit in 20..60 {
+                let parts = ParseMode::Html.split(text, limit);
+                assert!(parts.len() < 10, "{:?}", parts);
+                for part in &parts {
+                    assert!(text_len(part) <= limit, "{}", part);
+                    assert_nested_tags(part);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn markdown_nested_emphasis() {
+        assert_eq!(
+            ParseMode::MarkdownV2.render("**bold _italic_**"),
+            "*bold _italic_*"
+        );
+    }
+
+    #[test]
+    fn markdown_strikethrough() {
+        assert_eq!(ParseMode::MarkdownV2.render("~~s~~ a-b"), "~s~ a\\-b");
+    }
+
+    #[test]
+    fn markdown_nested_list() {
+        assert_eq!(
+            ParseMode::MarkdownV2.render("- a\n  - b\n    - c\n- d\n\n1. x\n2. y"),
+            "• a\n  ◦ b\n    ▪ c\n• d\n\n1\\. x\n2\\. y"
+        );
+    }
+
+    #[test]
+    fn markdown_quote() {
+        assert_eq!(
+            ParseMode::MarkdownV2.render("> a.\n> b\n>\n> - c"),
+            ">a\\.\n>b\n>\n>• c"
+        );
+    }
+
+    #[test]
+    fn markdown_code_block_interrupts_quote() {
+        assert_eq!(
+            ParseMode::MarkdownV2.render("> a\n> ```rust\n> let a = 1;\n> ```\n> b"),
+            ">a\n```rust\nlet a = 1;\n```\n>b"
+        );
+        let text = format!("> ```\n{}> ```", "> line\n".repeat(50));
+        for part in ParseMode::MarkdownV2.split(&text, 100) {
+            assert!(part.starts_with("```\nline\n") && part.ends_with("line\n```"));
+        }
+    }
+
+    #[test]
+    fn markdown_indented_code_block() {
+        assert_eq!(
+            ParseMode::MarkdownV2
+                .render("text\n\n    fn main() {\n\n        a_b();\n    }\n\nafter"),
+            "text\n\n```\nfn main() {\n\n    a_b();\n}\n```\n\nafter"
+        );
+        assert_eq!(
+            ParseMode::Html.render("\tif a < b:\n\t    pass"),
+            "<pre>if a &lt; b:\n    pass</pre>"
+        );
+        // an indented line continues a paragraph
+        assert_eq!(ParseMode::MarkdownV2.render("a\n    b"), "a\nb");
+    }
+
+    #[test]
+    fn markdown_table_aligns_cjk() {
+        assert_eq!(
+            ParseMode::MarkdownV2.render("| 名前 | 値 |\n|:--|--:|\n| 日本 | 1 |\n| ab | 200 |"),
+            "```\n名前 |  値\n-----+----\n日本 |   1\nab   | 200\n```"
+        );
+    }
+
+    #[test]
+    fn markdown_escapes_only_backticks_and_backslashes_in_pre() {
+        assert_eq!(
+            ParseMode::MarkdownV2.render("```rust\nlet s = \"`\\\";\na.b_c();\n\nx*y\n```"),
+            "```rust\nlet s = \"\\`\\\\\";\na.b_c();\n\nx*y\n```"
+        );
+    }
+
+    #[test]
+    fn degenerate_markdown_is_literal() {
+        for (text, markdown, html, plain) in [
+            ("```", "\\`\\`\\`", "```", "```"),
+            ("```rust", "\\`\\`\\`rust", "```rust", "```rust"),
+            ("```\n```", "\\`\\`\\`\n\\`\\`\\`", "```\n```", "```\n```"),
+            ("> ", "\\> ", "&gt; ", "> "),
+            (">", "\\>", "&gt;", ">"),
+            ("\r\n", "\r\n", "\r\n", "\r\n"),
+            (
+                "    indented code",
+                "```\nindented code\n```",
+                "<pre>indented code</pre>",
+                "indented code",
+            ),
+            (
+                "\ttab code",
+                "```\ntab code\n```",
+                "<pre>tab code</pre>",
+                "tab code",
+            ),
+            (
+                "> a\n>\n> ```",
+                ">a\n>\n>\\`\\`\\`",
+                "<blockquote>a\n\n```</blockquote>",
+                "a\n\n```",
+            ),
+        ] {
+            assert_eq!(ParseMode::MarkdownV2.render(text), markdown, "{:?}", text);
+            assert_eq!(ParseMode::Html.render(text), html, "{:?}", text);
+            assert_eq!(render_entities(text).0, plain, "{:?}", text);
+        }
+    }
+
+    /// Assert that the characters of `text` but whitespace are in `output` in
+    /// the same order.
+    fn assert_in_order(text: &str, output: &str) {
+        let mut rest = output.chars();
+        for c in text.chars().filter(|c| !c.is_whitespace()) {
+            assert!(
+                rest.any(|o| o == c),
+                "{:?} lost {:?} in {:?}",
+                text,
+                c,
+                output
+            );
+        }
+    }
+
+    /// A xorshift generator of random numbers.
+    fn random() -> impl FnMut() -> usize {
+        let mut seed: u64 = 0x9E37_79B9_7F4A_7C15;
+        move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed as usize
+        }
+    }
+
+    /// The unescaped outputs of every parse mode, whole and split.
+    fn unescaped_outputs(text: &str) -> Vec<String> {
+        let mut outputs = vec![render_entities(text).0];
+        for mode in [ParseMode::MarkdownV2, ParseMode::Html] {
+            outputs.push(mode.unescape(mode.render(text)));
+            outputs.push(
+                mode.split(text, 40)
+                    .iter()
+                    .map(|part| mode.unescape(part))
+                    .collect(),
+            );
+        }
+        outputs
+    }
+
+    #[test]
+    fn unparseable_markup_is_kept_in_order() {
+        const PIECES: [&str; 7] = ["ab", "日本", "😀", "42", " ", "  ", "\t"];
+        let mut next = random();
+        for _ in 0..2000 {
+            // every line starts with a word, and a special character is only
+            // once in a text, so none of them is markup
+            let mut special: Vec<char> = "*_`[]|~>#-+=.!(){}<&\"".chars().collect();
+            let mut text = String::from("ab");
+            for _ in 0..next() % 40 {
+                match next() % 4 {
+                    0 if !special.is_empty() => {
+                        text.push(special.swap_remove(next() % special.len()));
+                    }
+                    1 => text.push_str("\nab"),
+                    _ => text.push_str(PIECES[next() % PIECES.len()]),
+                }
+            }
+            for output in unescaped_outputs(&text) {
+                assert_in_order(&text, &output);
+            }
+        }
+    }
+
+    #[test]
+    fn converted_text_keeps_every_character() {
+        const PIECES: [&str; 34] = [
+            "ab", "日本", "😀", "42", "*", "**", "_", "__", "`", "```", "~~", "||", "[", "](", ")",
+            "<u>", "</u>", "<", "&", "\\", "# ", "> ", ">", "- ", "1. ", "|", "|:-:|", "---", "\n",
+            "\n\n", "\r\n", "    ", " ", "\t",
+        ];
+        let mut next = random();
+        for _ in 0..2000 {
+            let text: String = (0..next() % 30)
+                .map(|_| PIECES[next() % PIECES.len()])
+                .collect();
+            // markup is removed, but the rest of the text is in every output
+            let plain = render_entities(&text).0;
+            let markdown = ParseMode::MarkdownV2.unescape(ParseMode::MarkdownV2.render(&text));
+            for output in unescaped_outputs(&text) {
+                assert!(
+                    text.trim().is_empty() || !output.trim().is_empty(),
+                    "{:?}",
+                    text
+                );
+                assert_in_order(&plain, &output);
+            }
+            // MarkdownV2 also keeps the languages of code blocks and urls
+            for word in &PIECES[..4] {
+                assert!(
+                    markdown.matches(word).count() >= text.matches(word).count(),
+                    "{:?} lost {:?} in {:?}",
+                    text,
+                    word,
+                    markdown
+                );
+            }
+        }
     }
 }
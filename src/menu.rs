@@ -0,0 +1,328 @@
+use tg_flows::{InlineKeyboardButton, InlineKeyboardButtonKind, InlineKeyboardMarkup};
+
+use crate::persona::PersonaRegistry;
+use crate::settings;
+
+/// Telegram rejects inline buttons with callback data longer than 64 bytes.
+const CALLBACK_DATA_LIMIT: usize = 64;
+
+const PATH_SEPARATOR: char = '.';
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SettingTarget {
+    ChatLanguageModel,
+    UserLanguageModel,
+    ChatGroupMode,
+}
+
+/// Actions of the buttons under answers, the callback data of which is out
+/// of the menu tree.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AnswerAction {
+    Regenerate,
+    Continue,
+    Shorter,
+}
+
+impl AnswerAction {
+    const ROOT: &'static str = "answer";
+
+    fn name(&self) -> &'static str {
+        match self {
+            AnswerAction::Regenerate => "regenerate",
+            AnswerAction::Continue => "continue",
+            AnswerAction::Shorter => "shorter",
+        }
+    }
+
+    fn title(&self) -> &'static str {
+        match self {
+            AnswerAction::Regenerate => "🔄 Regenerate",
+            AnswerAction::Continue => "➡️ Continue",
+            AnswerAction::Shorter => "✂️ Shorter",
+        }
+    }
+
+    pub fn from_data(data: &str) -> Option<Self> {
+        let (root, name) = data.split_once(PATH_SEPARATOR)?;
+        if root != Self::ROOT {
+            return None;
+        }
+        [
+            AnswerAction::Regenerate,
+            AnswerAction::Continue,
+            AnswerAction::Shorter,
+        ]
+        .into_iter()
+        .find(|action| action.name() == name)
+    }
+
+    /// Keyboard with a button of each of the actions.
+    pub fn keyboard(actions: &[AnswerAction]) -> InlineKeyboardMarkup {
+        InlineKeyboardMarkup::default().append_row(
+            actions
+                .iter()
+                .map(|action| {
+                    MenuTree::button(
+                        action.title().to_owned(),
+                        format!("{}{}{}", Self::ROOT, PATH_SEPARATOR, action.name()),
+                    )
+                })
+                .collect::<Vec<_>>(),
+        )
+    }
+}
+
+pub enum MenuItem {
+    /// Open a submenu by editing the menu message in place.
+    Submenu(Menu),
+    /// Start a conversation with the persona of the id.
+    Persona(String),
+    /// Set a setting to the value, `None` clears it.
+    Setting {
+        title: String,
+        target: SettingTarget,
+        value: Option<String>,
+    },
+}
+
+pub struct Menu {
+    title: String,
+    text: String,
+    back: String,
+    rows: Vec<Vec<MenuItem>>,
+}
+
+impl Menu {
+    fn new(title: impl Into<String>, text: impl Into<String>) -> Self {
+        Self {
+            title: title.into(),
+            text: text.into(),
+            back: String::new(),
+            rows: vec![],
+        }
+    }
+
+    /// Title of the button going back to the parent menu.
+    fn back(mut self, title: impl Into<String>) -> Self {
+        self.back = title.into();
+        self
+    }
+
+    fn row(mut self, items: Vec<MenuItem>) -> Self {
+        self.rows.push(items);
+        self
+    }
+
+    fn child(&self, index: usize) -> Option<&MenuItem> {
+        self.rows.iter().flatten().nth(index)
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+}
+
+fn persona(id: &str) -> MenuItem {
+    MenuItem::Persona(id.to_owned())
+}
+
+fn setting(title: &str, target: SettingTarget, value: Option<&str>) -> MenuItem {
+    MenuItem::Setting {
+        title: title.to_owned(),
+        target,
+        value: value.map(String::from),
+    }
+}
+
+pub enum MenuNode<'a> {
+    Menu(&'a Menu),
+    Persona(&'a str),
+    Setting {
+        target: SettingTarget,
+        value: Option<&'a str>,
+    },
+}
+
+/// Menus of the bot, a node is addressed by the id of its root menu followed
+/// by the index of the node in each level, e.g. `nihongo.2.0`.
+pub struct MenuTree {
+    roots: Vec<(&'static str, Menu)>,
+}
+
+impl Default for MenuTree {
+    fn default() -> Self {
+        let nihongo = Menu::new("日本語", "どのようにおてつだいでくますか？")
+            .row(vec![
+                persona("nihongo-translate"),
+                persona("nihongo-explain"),
+            ])
+            .row(vec![MenuItem::Submenu(
+                Menu::new("模擬会話", "モック会話しています、何な場面をほしいですか")
+                    .back("戻る")
+                    .row(vec![persona("nihongo-scene-mock-cafe")])
+                    .row(vec![persona("nihongo-scene-mock-restaurant")])
+                    .row(vec![persona("nihongo-scene-mock-clothes-shop")])
+                    .row(vec![persona("nihongo-scene-mock-street")])
+                    .row(vec![persona("nihongo-scene-mock-small-talk")]),
+            )]);
+
+        let chat_lm = SettingTarget::ChatLanguageModel;
+        let user_lm = SettingTarget::UserLanguageModel;
+        let settings = Menu::new("settings", "Choose the language model for this chat.")
+            .row(vec![setting("gpt3.5-turbo", chat_lm, Some("gpt3.5-turbo"))])
+            .row(vec![setting(
+                "gpt3.5-turbo-16k",
+                chat_lm,
+                Some("gpt3.5-turbo-16k"),
+            )])
+            .row(vec![setting("gpt4", chat_lm, Some("gpt4"))])
+            .row(vec![MenuItem::Submenu(
+                Menu::new("only for me", "Choose the language model only for you.")
+                    .back("back to chat settings")
+                    .row(vec![setting("gpt3.5-turbo", user_lm, Some("gpt3.5-turbo"))])
+                    .row(vec![setting(
+                        "gpt3.5-turbo-16k",
+                        user_lm,
+                        Some("gpt3.5-turbo-16k"),
+                    )])
+                    .row(vec![setting("gpt4", user_lm, Some("gpt4"))])
+                    .row(vec![setting("follow chat", user_lm, None)]),
+            )])
+            .row(vec![MenuItem::Submenu(
+                Menu::new("group mode", "Choose when to answer in a group.")
+                    .back("back to chat settings")
+                    .row(vec![setting(
+                        "mentions and replies",
+                        SettingTarget::ChatGroupMode,
+                        Some(settings::GROUP_MODE_MENTION),
+                    )])
+                    .row(vec![setting(
+                        "all messages",
+                        SettingTarget::ChatGroupMode,
+                        Some(settings::GROUP_MODE_ALL),
+                    )]),
+            )]);
+
+        Self {
+            roots: vec![("nihongo", nihongo), ("settings", settings)],
+        }
+    }
+}
+
+impl MenuTree {
+    pub fn resolve(&self, path: &str) -> Option<MenuNode<'_>> {
+        let mut parts = path.split(PATH_SEPARATOR);
+        let root = parts.next()?;
+        let indices: Vec<usize> = parts.map(|part| part.parse().ok()).collect::<Option<_>>()?;
+
+        let mut menu = &self.roots.iter().find(|(id, _)| *id == root)?.1;
+        for (i, index) in indices.iter().enumerate() {
+            let is_last = i + 1 == indices.len();
+            match menu.child(*index)? {
+                MenuItem::Submenu(submenu) => menu = submenu,
+                MenuItem::Persona(id) if is_last => return Some(MenuNode::Persona(id)),
+                MenuItem::Setting { target, value, .. } if is_last => {
+                    return Some(MenuNode::Setting {
+                        target: *target,
+                        value: value.as_deref(),
+                    })
+                }
+                _ => return None,
+            }
+        }
+        Some(MenuNode::Menu(menu))
+    }
+
+    /// Ids of the personas in every menu.
+    #[cfg(test)]
+    pub fn persona_ids(&self) -> Vec<&str> {
+        fn collect<'a>(menu: &'a Menu, ids: &mut Vec<&'a str>) {
+            for item in menu.rows.iter().flatten() {
+                match item {
+                    MenuItem::Submenu(submenu) => collect(submenu, ids),
+                    MenuItem::Persona(id) => ids.push(id),
+                    MenuItem::Setting { .. } => {}
+                }
+            }
+        }
+        let mut ids = vec![];
+        for (_, menu) in self.roots.iter() {
+            collect(menu, &mut ids);
+        }
+        ids
+    }
+
+    pub fn parent_path(path: &str) -> &str {
+        path.rsplit_once(PATH_SEPARATOR)
+            .map(|(parent, _)| parent)
+            .unwrap_or(path)
+    }
+
+    /// Build the keyboard of the menu at `path`, settings for which
+    /// `is_selected` returns true are marked as selected.
+    pub fn keyboard<F>(
+        &self,
+        path: &str,
+        personas: &PersonaRegistry,
+        is_selected: F,
+    ) -> Option<InlineKeyboardMarkup>
+    where
+        F: Fn(SettingTarget, Option<&str>) -> bool,
+    {
+        let menu = match self.resolve(path)? {
+            MenuNode::Menu(menu) => menu,
+            _ => return None,
+        };
+
+        let mut index = 0;
+        let mut keyboard = InlineKeyboardMarkup::default();
+        for row in menu.rows.iter() {
+            let mut buttons = vec![];
+            for item in row.iter() {
+                let title = match item {
+                    MenuItem::Submenu(submenu) => submenu.title.clone(),
+                    MenuItem::Persona(id) => match personas.get(id) {
+                        Some(persona) => persona.title.clone(),
+                        None => {
+                            log::warn!("skip the unknown persona in the menu: {}", id);
+                            index += 1;
+                            continue;
+                        }
+                    },
+                    MenuItem::Setting {
+                        title,
+                        target,
+                        value,
+                    } => {
+                        if is_selected(*target, value.as_deref()) {
+                            format!("✓ {}", title)
+                        } else {
+                            title.clone()
+                        }
+                    }
+                };
+                buttons.push(Self::button(
+                    title,
+                    format!("{}{}{}", path, PATH_SEPARATOR, index),
+                ));
+                index += 1;
+            }
+            keyboard = keyboard.append_row(buttons);
+        }
+        if path.contains(PATH_SEPARATOR) {
+            keyboard = keyboard.append_row(vec![Self::button(
+                menu.back.clone(),
+                Self::parent_path(path).to_owned(),
+            )]);
+        }
+        Some(keyboard)
+    }
+
+    fn button(title: String, data: String) -> InlineKeyboardButton {
+        if data.len() > CALLBACK_DATA_LIMIT {
+            log::error!("callback data is too long: {}", data);
+        }
+        InlineKeyboardButton::new(title, InlineKeyboardButtonKind::CallbackData(data))
+    }
+}
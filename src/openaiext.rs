@@ -0,0 +1,347 @@
+use std::io::Write;
+
+use anyhow::bail;
+use http_req::{
+    request::{Method, Request},
+    uri::Uri,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::multipart::Multipart;
+use crate::tokens::{estimate_tokens, IMAGE_TOKENS, MESSAGE_OVERHEAD_TOKENS};
+
+const CHAT_COMPLETIONS_URL: &str = "https://api.openai.com/v1/chat/completions";
+
+const TRANSCRIPTIONS_URL: &str = "https://api.openai.com/v1/audio/transcriptions";
+
+const SPEECH_URL: &str = "https://api.openai.com/v1/audio/speech";
+
+/// Transcriptions and speech are counted in usage and quotas by these names.
+pub const TRANSCRIPTION_MODEL: &str = "whisper-1";
+
+pub const SPEECH_MODEL: &str = "tts-1";
+
+/// The speech endpoint rejects longer inputs.
+const SPEECH_INPUT_LIMIT: usize = 4096;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ImageUrl {
+    pub url: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+/// The content of a message is either plain text, or parts of text and
+/// images for vision models.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ChatContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub role: String,
+    pub content: ChatContent,
+}
+
+impl ChatMessage {
+    pub fn system(content: impl Into<String>) -> Self {
+        Self {
+            role: "system".into(),
+            content: ChatContent::Text(content.into()),
+        }
+    }
+
+    pub fn user(content: impl Into<String>) -> Self {
+        Self {
+            role: "user".into(),
+            content: ChatContent::Text(content.into()),
+        }
+    }
+
+    /// A user message with images, which are urls or `data:` urls of the
+    /// encoded images.
+    pub fn user_with_images(text: impl Into<String>, images: Vec<String>) -> Self {
+        let text: String = text.into();
+        let mut parts: Vec<ContentPart> = images
+            .into_iter()
+            .map(|url| ContentPart::ImageUrl {
+                image_url: ImageUrl { url },
+            })
+            .collect();
+        if !text.is_empty() {
+            parts.push(ContentPart::Text { text });
+        }
+        Self {
+            role: "user".into(),
+            content: ChatContent::Parts(parts),
+        }
+    }
+
+    pub fn assistant(content: impl Into<String>) -> Self {
+        Self {
+            role: "assistant".into(),
+            content: ChatContent::Text(content.into()),
+        }
+    }
+
+    /// The text of the message without images.
+    pub fn text(&self) -> String {
+        match self.content {
+            ChatContent::Text(ref text) => text.clone(),
+            ChatContent::Parts(ref parts) => parts
+                .iter()
+                .filter_map(|part| match part {
+                    ContentPart::Text { text } => Some(text.as_str()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
+
+    pub fn tokens(&self) -> usize {
+        estimate_tokens(&self.text())
+            + self.image_urls().len() * IMAGE_TOKENS
+            + MESSAGE_OVERHEAD_TOKENS
+    }
+
+    pub fn image_urls(&self) -> Vec<String> {
+        match self.content {
+            ChatContent::Text(_) => vec![],
+            ChatContent::Parts(ref parts) => parts
+                .iter()
+                .filter_map(|part| match part {
+                    ContentPart::ImageUrl { image_url } => Some(image_url.url.clone()),
+                    _ => None,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Flatten a conversation into a system prompt and a question, for APIs
+/// which only take these two. Earlier messages go into the system prompt as
+/// a transcript, images are left out.
+pub fn flatten_messages(messages: &[ChatMessage]) -> (String, String) {
+    let (system, rest) = match messages.split_first() {
+        Some((first, rest)) if first.role == "system" => (first.text(), rest),
+        _ => (String::new(), messages),
+    };
+    let (question, earlier) = match rest.split_last() {
+        Some((last, earlier)) => (last.text(), earlier),
+        None => (String::new(), rest),
+    };
+    if earlier.is_empty() {
+        return (system, question);
+    }
+
+    let transcript: Vec<String> = earlier
+        .iter()
+        .map(|m| format!("{}: {}", m.role, m.text()))
+        .collect();
+    (
+        format!(
+            "{}\n\nThe conversation so far:\n{}",
+            system,
+            transcript.join("\n")
+        ),
+        question,
+    )
+}
+
+/// Collects server-sent events of a streaming chat completion and reports
+/// the accumulated answer every time a new delta arrives.
+struct ChatStreamWriter<F: FnMut(&str)> {
+    buffer: Vec<u8>,
+    answer: String,
+    unexpected: String,
+    on_delta: F,
+}
+
+impl<F: FnMut(&str)> ChatStreamWriter<F> {
+    fn handle_line(&mut self, line: &str) {
+        let data = match line.strip_prefix("data:") {
+            Some(data) => data.trim(),
+            None => {
+                self.unexpected.push_str(line);
+                return;
+            }
+        };
+        if data == "[DONE]" {
+            return;
+        }
+        let delta = serde_json::from_str::<serde_json::Value>(data)
+            .ok()
+            .and_then(|v| {
+                v["choices"][0]["delta"]["content"]
+                    .as_str()
+                    .map(String::from)
+            });
+        if let Some(delta) = delta {
+            self.answer.push_str(&delta);
+            (self.on_delta)(&self.answer);
+        }
+    }
+}
+
+impl<F: FnMut(&str)> Write for ChatStreamWriter<F> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        while let Some(pos) = self.buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=pos).collect();
+            self.handle_line(String::from_utf8_lossy(&line).trim_end());
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Request a chat completion with `stream: true`, calling `on_delta` with the
+/// answer received so far, and return the complete answer.
+pub fn chat_completion_stream<F>(
+    api_key: &str,
+    model: &str,
+    messages: &[ChatMessage],
+    on_delta: F,
+) -> anyhow::Result<String>
+where
+    F: FnMut(&str),
+{
+    let body = serde_json::json!({
+        "model": model,
+        "messages": messages,
+        "stream": true,
+    })
+    .to_string();
+    let uri = Uri::try_from(CHAT_COMPLETIONS_URL)?;
+    let bearer = format!("Bearer {}", api_key);
+
+    let mut writer = ChatStreamWriter {
+        buffer: vec![],
+        answer: String::new(),
+        unexpected: String::new(),
+        on_delta,
+    };
+    let resp = Request::new(&uri)
+        .method(Method::POST)
+        .header("Authorization", &bearer)
+        .header("Content-Type", "application/json")
+        .header("Content-Length", &body.len())
+        .body(body.as_bytes())
+        .send(&mut writer)?;
+
+    if !resp.status_code().is_success() {
+        bail!(
+            "chat completion failed: {} {}",
+            resp.status_code(),
+            writer.unexpected
+        );
+    }
+
+    Ok(writer.answer)
+}
+
+fn post(api_key: &str, url: &str, content_type: &str, body: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let uri = Uri::try_from(url)?;
+    let bearer = format!("Bearer {}", api_key);
+    let mut writer = vec![];
+    let resp = Request::new(&uri)
+        .method(Method::POST)
+        .header("Authorization", &bearer)
+        .header("Content-Type", content_type)
+        .header("Content-Length", &body.len())
+        .body(body)
+        .send(&mut writer)?;
+
+    if !resp.status_code().is_success() {
+        bail!(
+            "request to {} failed: {} {}",
+            url,
+            resp.status_code(),
+            String::from_utf8_lossy(&writer)
+        );
+    }
+    Ok(writer)
+}
+
+/// Transcribe an audio file, the format is told by the extension of
+/// `file_name`, e.g. `voice.ogg`.
+pub fn transcribe(api_key: &str, file_name: &str, audio: &[u8]) -> anyhow::Result<String> {
+    let (content_type, body) = Multipart::default()
+        .text("model", TRANSCRIPTION_MODEL)
+        .text("response_format", "text")
+        .file("file", file_name, "application/octet-stream", audio)
+        .finish();
+    let text = post(api_key, TRANSCRIPTIONS_URL, &content_type, &body)?;
+    Ok(String::from_utf8_lossy(&text).trim().to_owned())
+}
+
+/// Synthesize speech of the text as an OGG file encoded with OPUS, which
+/// Telegram shows as a voice message.
+pub fn speech(api_key: &str, voice: &str, text: &str) -> anyhow::Result<Vec<u8>> {
+    let input: String = text.chars().take(SPEECH_INPUT_LIMIT).collect();
+    let body = serde_json::json!({
+        "model": SPEECH_MODEL,
+        "voice": voice,
+        "input": input,
+        "response_format": "opus",
+    })
+    .to_string();
+    post(api_key, SPEECH_URL, "application/json", body.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream(chunks: &[&[u8]]) -> (String, Vec<String>, String) {
+        let mut deltas = vec![];
+        let mut writer = ChatStreamWriter {
+            buffer: vec![],
+            answer: String::new(),
+            unexpected: String::new(),
+            on_delta: |answer: &str| deltas.push(answer.to_owned()),
+        };
+        for chunk in chunks {
+            writer.write_all(chunk).unwrap();
+        }
+        let (answer, unexpected) = (writer.answer, writer.unexpected);
+        (answer, deltas, unexpected)
+    }
+
+    #[test]
+    fn stream_joins_split_data_lines() {
+        let event = "data: {\"choices\":[{\"delta\":{\"content\":\"你好\"}}]}\n\n";
+        let (head, tail) = event.as_bytes().split_at(event.find('好').unwrap() + 1);
+        let (answer, deltas, unexpected) = stream(&[
+            b"data: {\"choices\":[{\"delta\":{\"role\":\"assistant\"}}]}\n\nda",
+            b"ta: {\"choices\":[{\"delta\":{\"content\":\"Hi, \"}}]}\n\n",
+            head,
+            tail,
+            b"data: [DONE]\n\n",
+        ]);
+        assert_eq!(answer, "Hi, 你好");
+        assert_eq!(deltas, vec!["Hi, ", "Hi, 你好"]);
+        assert_eq!(unexpected, "");
+    }
+
+    #[test]
+    fn stream_keeps_unexpected_lines() {
+        let (answer, deltas, unexpected) =
+            stream(&[b"{\"error\": {\"message\": \"invalid key\"}}\n"]);
+        assert_eq!(answer, "");
+        assert!(deltas.is_empty());
+        assert_eq!(unexpected, "{\"error\": {\"message\": \"invalid key\"}}");
+    }
+}
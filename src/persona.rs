@@ -0,0 +1,292 @@
+use std::collections::HashMap;
+
+use anyhow::bail;
+use serde::Deserialize;
+
+pub const DEFAULT_PERSONA: &str = "default";
+
+const BUILTIN_PERSONAS: &str = include_str!("personas.toml");
+
+/// Guard against parents that refer to each other.
+const MAX_PERSONA_DEPTH: usize = 8;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Persona {
+    pub id: String,
+    pub title: String,
+    #[serde(default)]
+    pub parents: Vec<String>,
+    #[serde(default)]
+    pub system: String,
+    #[serde(default)]
+    pub entry: String,
+    /// Voice of the spoken replies, e.g. `nova`, replies are only sent as
+    /// text without it.
+    #[serde(default)]
+    pub voice: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct PersonaConfig {
+    persona: Vec<Persona>,
+}
+
+pub struct PersonaRegistry {
+    personas: HashMap<String, Persona>,
+    variables: HashMap<String, String>,
+}
+
+impl Default for PersonaRegistry {
+    /// The builtin personas, only an empty default persona is left if they
+    /// can't be parsed, rather than failing every update.
+    fn default() -> Self {
+        Self::parse(BUILTIN_PERSONAS, true).unwrap_or_else(|e| {
+            log::error!("failed to parse builtin personas: {}", e);
+            let persona = Persona {
+                id: DEFAULT_PERSONA.to_owned(),
+                title: "Default".to_owned(),
+                parents: vec![],
+                system: String::new(),
+                entry: String::new(),
+                voice: None,
+            };
+            Self {
+                personas: HashMap::from([(persona.id.clone(), persona)]),
+                variables: HashMap::new(),
+            }
+        })
+    }
+}
+
+impl PersonaRegistry {
+    /// Load personas from the `personas` environment variable, and fall back
+    /// to the builtin ones.
+    pub fn load() -> Self {
+        match std::env::var("personas") {
+            Ok(text) => {
+                let is_json = text.trim_start().starts_with('{');
+                Self::parse(&text, !is_json).unwrap_or_else(|e| {
+                    log::error!("failed to load personas, use builtin ones: {}", e);
+                    Self::default()
+                })
+            }
+            Err(_) => Self::default(),
+        }
+    }
+
+    fn parse(text: &str, is_toml: bool) -> anyhow::Result<Self> {
+        let config: PersonaConfig = if is_toml {
+            toml::from_str(text)?
+        } else {
+            serde_json::from_str(text)?
+        };
+
+        let personas: HashMap<_, _> = config
+            .persona
+            .into_iter()
+            .map(|persona| (persona.id.clone(), persona))
+            .collect();
+        if !personas.contains_key(DEFAULT_PERSONA) {
+            bail!("persona \"{}\" is missing", DEFAULT_PERSONA);
+        }
+        for persona in personas.values() {
+            if let Some(parent) = persona.parents.iter().find(|p| !personas.contains_key(*p)) {
+                bail!(
+                    "unknown parent \"{}\" of persona \"{}\"",
+                    parent,
+                    persona.id
+                );
+            }
+        }
+
+        Ok(Self {
+            personas,
+            variables: HashMap::new(),
+        })
+    }
+
+    /// Replace the system prompt of the default persona, which every other
+    /// persona builds on.
+    pub fn set_base_prompt(&mut self, prompt: impl Into<String>) {
+        if let Some(persona) = self.personas.get_mut(DEFAULT_PERSONA) {
+            persona.system = prompt.into();
+        }
+    }
+
+    /// Set the value replacing `{name}` in system prompts.
+    pub fn set_variable(&mut self, name: &str, value: impl Into<String>) {
+        self.variables.insert(name.to_owned(), value.into());
+    }
+
+    /// Look up a persona, ids stored by older versions of the bot are in
+    /// CamelCase, e.g. `NihongoSceneMockCafe` for `nihongo-scene-mock-cafe`.
+    pub fn get(&self, id: &str) -> Option<&Persona> {
+        self.personas.get(id).or_else(|| {
+            let mut kebab = String::new();
+            for (i, c) in id.chars().enumerate() {
+                if c.is_ascii_uppercase() && i > 0 {
+                    kebab.push('-');
+                }
+                kebab.push(c.to_ascii_lowercase());
+            }
+            self.personas.get(&kebab)
+        })
+    }
+
+    /// Resolve a persona id, unknown ids fall back to the default persona.
+    pub fn resolve(&self, id: &str) -> &Persona {
+        self.get(id)
+            .unwrap_or_else(|| &self.personas[DEFAULT_PERSONA])
+    }
+
+    /// Compose the system prompt of a persona from its parents and itself.
+    pub fn prompt(&self, id: &str) -> String {
+        let mut prompts = vec![];
+        self.collect_prompts(self.resolve(id), 0, &mut prompts);
+        self.variables
+            .iter()
+            .fold(prompts.join("\n"), |prompt, (name, value)| {
+                prompt.replace(&format!("{{{}}}", name), value)
+            })
+    }
+
+    /// The voice of a persona, which is inherited from its parents.
+    pub fn voice(&self, id: &str) -> Option<&str> {
+        self.find_voice(self.resolve(id), 0)
+    }
+
+    fn find_voice<'a>(&'a self, persona: &'a Persona, depth: usize) -> Option<&'a str> {
+        if depth > MAX_PERSONA_DEPTH {
+            return None;
+        }
+        persona.voice.as_deref().or_else(|| {
+            persona
+                .parents
+                .iter()
+                .filter_map(|parent| self.personas.get(parent))
+                .find_map(|parent| self.find_voice(parent, depth + 1))
+        })
+    }
+
+    fn collect_prompts<'a>(
+        &'a self,
+        persona: &'a Persona,
+        depth: usize,
+        prompts: &mut Vec<&'a str>,
+    ) {
+        if depth > MAX_PERSONA_DEPTH {
+            log::warn!("persona \"{}\" is nested too deep", persona.id);
+            return;
+        }
+        for parent in persona.parents.iter() {
+            if let Some(parent) = self.personas.get(parent) {
+                self.collect_prompts(parent, depth + 1, prompts);
+            }
+        }
+        prompts.push(persona.system.as_str());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PERSONAS: &str = r#"
+        [[persona]]
+        id = "default"
+        title = "Default"
+        system = "I'm {bot_name} by {creator}."
+
+        [[persona]]
+        id = "scene"
+        title = "Scene"
+        parents = ["default"]
+        system = "Act a scene."
+        voice = "nova"
+
+        [[persona]]
+        id = "scene-cafe"
+        title = "Cafe"
+        parents = ["scene"]
+        system = "At a cafe."
+    "#;
+
+    fn registry() -> PersonaRegistry {
+        PersonaRegistry::parse(PERSONAS, true).unwrap()
+    }
+
+    #[test]
+    fn parse_requires_default() {
+        let text = r#"{"persona": [{"id": "other", "title": "Other"}]}"#;
+        let e = PersonaRegistry::parse(text, false).err().unwrap();
+        assert_eq!(e.to_string(), "persona \"default\" is missing");
+    }
+
+    #[test]
+    fn parse_rejects_unknown_parent() {
+        let text = PERSONAS.replace(r#"parents = ["scene"]"#, r#"parents = ["nowhere"]"#);
+        let e = PersonaRegistry::parse(&text, true).err().unwrap();
+        assert_eq!(
+            e.to_string(),
+            "unknown parent \"nowhere\" of persona \"scene-cafe\""
+        );
+    }
+
+    #[test]
+    fn get_legacy_camel_case_id() {
+        let personas = registry();
+        assert_eq!(personas.get("SceneCafe").unwrap().id, "scene-cafe");
+        assert!(personas.get("Missing").is_none());
+        assert_eq!(personas.resolve("Missing").id, DEFAULT_PERSONA);
+    }
+
+    #[test]
+    fn prompt_composes_parents_and_variables() {
+        let mut personas = registry();
+        personas.set_variable("bot_name", "Cheese");
+        personas.set_variable("creator", "Chase");
+        assert_eq!(
+            personas.prompt("scene-cafe"),
+            "I'm Cheese by Chase.\nAct a scene.\nAt a cafe."
+        );
+        personas.set_base_prompt("Be {bot_name}.");
+        assert_eq!(personas.prompt("scene"), "Be Cheese.\nAct a scene.");
+    }
+
+    #[test]
+    fn prompt_stops_at_max_depth() {
+        // parents referring to each other
+        let text = PERSONAS.replace(r#"parents = ["default"]"#, r#"parents = ["scene-cafe"]"#);
+        let personas = PersonaRegistry::parse(&text, true).unwrap();
+        let prompt = personas.prompt("scene-cafe");
+        assert_eq!(
+            prompt.matches("At a cafe.").count(),
+            MAX_PERSONA_DEPTH / 2 + 1
+        );
+        assert_eq!(personas.voice("default"), None);
+    }
+
+    #[test]
+    fn voice_is_inherited() {
+        let personas = registry();
+        assert_eq!(personas.voice("scene-cafe"), Some("nova"));
+        assert_eq!(personas.voice("scene"), Some("nova"));
+        assert_eq!(personas.voice(DEFAULT_PERSONA), None);
+    }
+
+    #[test]
+    fn builtin_personas_resolve_every_reference() {
+        let personas = PersonaRegistry::parse(BUILTIN_PERSONAS, true).unwrap();
+        let menus = crate::menu::MenuTree::default();
+        let ids = menus
+            .persona_ids()
+            .into_iter()
+            .chain(crate::tgbot::INLINE_PERSONAS);
+        for id in ids {
+            assert_eq!(
+                personas.get(id).map(|persona| persona.id.as_str()),
+                Some(id)
+            );
+        }
+    }
+}
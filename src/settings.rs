@@ -0,0 +1,155 @@
+use crate::markdown::ParseMode;
+use openai_flows::chat::ChatModel;
+use tg_flows::{ChatId, UserId};
+
+const DEFAULT_LANGUAGE_MODEL: &str = "gpt4";
+
+/// Model answering conversations with images, none of `LANGUAGE_MODELS` can
+/// see images.
+const DEFAULT_VISION_MODEL: &str = "gpt-4o";
+
+const LANGUAGE_MODEL_KEY: &str = "language.model";
+
+const SYSTEM_PROMPT_KEY: &str = "system_prompt";
+
+const GROUP_MODE_KEY: &str = "group.mode";
+
+/// In group chats, only answer when the bot is mentioned, commanded or
+/// replied to.
+pub const GROUP_MODE_MENTION: &str = "mention";
+
+/// In group chats, answer every message.
+pub const GROUP_MODE_ALL: &str = "all";
+
+pub const LANGUAGE_MODELS: [&str; 3] = ["gpt3.5-turbo", "gpt3.5-turbo-16k", "gpt4"];
+
+#[derive(Clone, Copy, Debug)]
+pub enum SettingsScope {
+    Deployment,
+    Chat(ChatId),
+    User(UserId),
+}
+
+impl SettingsScope {
+    fn key(&self, name: &str) -> String {
+        match self {
+            SettingsScope::Deployment => format!("settings.{}", name),
+            SettingsScope::Chat(id) => format!("settings--chat-{}.{}", id, name),
+            SettingsScope::User(id) => format!("settings--user-{}.{}", id, name),
+        }
+    }
+
+    fn get(&self, name: &str) -> Option<serde_json::Value> {
+        store_flows::get(&self.key(name))
+    }
+
+    fn set(&self, name: &str, value: serde_json::Value) {
+        let key = self.key(name);
+        log::info!("set settings, key: {}, value: {}", key, value);
+        store_flows::set(&key, value, None);
+    }
+
+    fn del(&self, name: &str) {
+        let key = self.key(name);
+        log::info!("delete settings, key: {}", key);
+        store_flows::del(&key);
+    }
+
+    pub fn get_language_model(&self) -> Option<String> {
+        self.get(LANGUAGE_MODEL_KEY)
+            .and_then(|v| v.as_str().map(|s| s.to_owned()))
+    }
+
+    pub fn set_language_model(&self, lm: &str) {
+        self.set(LANGUAGE_MODEL_KEY, serde_json::Value::String(lm.to_owned()))
+    }
+
+    pub fn clear_language_model(&self) {
+        self.del(LANGUAGE_MODEL_KEY)
+    }
+
+    pub fn get_group_mode(&self) -> Option<String> {
+        self.get(GROUP_MODE_KEY)
+            .and_then(|v| v.as_str().map(|s| s.to_owned()))
+    }
+
+    pub fn set_group_mode(&self, mode: &str) {
+        self.set(GROUP_MODE_KEY, serde_json::Value::String(mode.to_owned()))
+    }
+
+    pub fn get_system_prompt(&self) -> Option<String> {
+        self.get(SYSTEM_PROMPT_KEY)
+            .and_then(|v| v.as_str().map(|s| s.to_owned()))
+    }
+
+    pub fn set_system_prompt(&self, prompt: &str) {
+        self.set(
+            SYSTEM_PROMPT_KEY,
+            serde_json::Value::String(prompt.to_owned()),
+        )
+    }
+}
+
+/// Resolve the language model in the order of user > chat > deployment default,
+/// the deployment default set by admins takes precedence over the env.
+pub fn resolve_language_model(chat_id: ChatId, user_id: Option<UserId>) -> String {
+    user_id
+        .and_then(|id| SettingsScope::User(id).get_language_model())
+        .or_else(|| SettingsScope::Chat(chat_id).get_language_model())
+        .unwrap_or_else(default_language_model)
+}
+
+pub fn default_language_model() -> String {
+    SettingsScope::Deployment
+        .get_language_model()
+        .or_else(|| std::env::var("language_model").ok())
+        .unwrap_or(DEFAULT_LANGUAGE_MODEL.to_owned())
+}
+
+pub fn resolve_group_mode(chat_id: ChatId) -> String {
+    SettingsScope::Chat(chat_id)
+        .get_group_mode()
+        .or_else(|| std::env::var("group_mode").ok())
+        .unwrap_or(GROUP_MODE_MENTION.to_owned())
+}
+
+pub fn chat_model(lm: &str) -> ChatModel {
+    match lm {
+        "gpt4" => ChatModel::GPT4,
+        "gpt3.5-turbo" => ChatModel::GPT35Turbo,
+        _ => ChatModel::GPT35Turbo16K,
+    }
+}
+
+pub fn api_model(lm: &str) -> &'static str {
+    match lm {
+        "gpt4" => "gpt-4",
+        "gpt3.5-turbo" => "gpt-3.5-turbo",
+        _ => "gpt-3.5-turbo-16k",
+    }
+}
+
+pub fn vision_model() -> String {
+    std::env::var("vision_model").unwrap_or(DEFAULT_VISION_MODEL.to_owned())
+}
+
+/// The formatting of answers, `parse_mode` is `MarkdownV2` by default,
+/// `HTML`, or `entities`.
+pub fn parse_mode() -> ParseMode {
+    std::env::var("parse_mode")
+        .ok()
+        .and_then(|name| ParseMode::from_name(&name))
+        .unwrap_or(ParseMode::MarkdownV2)
+}
+
+/// Tokens in the context window of a model of the OpenAI API.
+pub fn context_window(model: &str) -> usize {
+    match model {
+        "gpt-3.5-turbo" => 4096,
+        "gpt-3.5-turbo-16k" => 16384,
+        "gpt-4" => 8192,
+        m if m.starts_with("gpt-4o") || m.starts_with("gpt-4-turbo") => 128000,
+        m if m.starts_with("gpt-4-32k") => 32768,
+        _ => 8192,
+    }
+}
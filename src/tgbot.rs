@@ -1,319 +1,109 @@
-use std::fmt;
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    time::{Duration, Instant},
+};
 
-use crate::tgext::TgExt;
+use crate::access::{AccessControl, AccessScope};
+use crate::command::{self, ParsedCommand, TgBotCommand};
+use crate::conversation::{Conversation, Role, Turn};
+use crate::document::{self, Document};
+use crate::markdown::{self, MessageEntity, ParseMode, MESSAGE_LENGTH_LIMIT};
+use crate::menu::{AnswerAction, MenuNode, MenuTree, SettingTarget};
+use crate::openaiext::{self, ChatMessage};
+use crate::persona::{PersonaRegistry, DEFAULT_PERSONA};
+use crate::settings::{self, SettingsScope};
+use crate::tgext::{self, TgExt};
+use crate::tokens::estimate_tokens;
+use crate::usage::{self, Quotas, UsageScope, UsageWindow};
 use anyhow::bail;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chrono::Utc;
 use flowsnet_platform_sdk::logger;
-use openai_flows::{
-    chat::{ChatModel, ChatOptions},
-    OpenAIFlows,
-};
+use openai_flows::{chat::ChatOptions, OpenAIFlows};
 use serde::{Deserialize, Serialize};
 use tg_flows::{
-    BotCommand, CallbackQuery, ChatId, ForceReply, InlineKeyboardButton, InlineKeyboardMarkup,
-    Message, ReplyMarkup, Telegram, Update, UpdateKind,
+    BotCommand, BotCommandScope, CallbackQuery, ChatId, ForceReply, InlineQuery, Message,
+    MessageId, Recipient, ReplyMarkup, Telegram, Update, UpdateKind, UserId,
 };
 
-const DEFAULT_PROMPT: &str = r#"
-Your name is "Cheese" and you are working as a jotting pal to help on Telegram. 
-You can answer questions, help clients learn japanese and show a help message.
-your creator is Chase Zhang, you are based on OpenAI's ChatGPT.
-You should double check the fact of your answer carefully before replying a message
-and make sure it is acurate.
-You should format your answers into markdown format if necessary.
-If you answer includes codeblocks, please make sure you will specify the name
-of the programming language with proper syntax in markdown format.
-"#;
-
-const NIHONGO_TRANSLATE_PROMPT: &str = r#"
-You are now helping the user to learn Japanese.
-You should act as a translate machine and please translate everything the user sent to you into Japanese direcly.
-You can provide explanation on keywords in the Japanese translation provide pronunciation in hiragana.
-If the user sent you Japanese, you should translate them into English and correct the user if there is any obvious mistake.
-When providing pronunciation of Japanese, please use hiragana or katakana instead of romaji.
-"#;
-
-const NIHONGO_EXPLAIN_PROMPT: &str = r#"
-You are now helping the user to learn Japanese.
-If the user sent you a piece of text in Japanese, you should explain the grammar and keywords.
-You can explain by break down the sentences and provide pronounce annotation in hiragana.
-If the user ask you a question in English, you should translate it into Japanese and explain your translation.
-You can also answer the user's chat from your own knowledge.
-You are encouraged to provide background information of a famous historical place.
-If you feel there is a better way to say something, feel free to correct the user.
-"#;
-
-const NIHONGO_MOCK_SCENE_PROMPT: &str = r#"
-You are now helping the users to learn Japanese.
-You should always speak Japanese in the conversation.
-You are now in mock conversation mode, in this mode, you should act as a role in a conversation scene.
-When the user send you a message, you should reply based on your role.
-If what the user has sent you is obviously not correct in terms of grammar or usage of words, you can first correct the users and provide an explanation.
-If you are replying to the user with some rarely used words, please provide the translation of them after the reply.
-If the user send you a message in English, please tell the user how to express the same meaning in Japanese before replying under your role.
-"#;
-
-const NIHONGO_MOCK_SCENE_CAFE_PROMPT: &str = r#"
-Your role is defined as follow:
-You are a waiter in a cafe.
-The cafe provide all kinds of coffee from espresso to pour over.
-The cafe also sell baked whole beans.
-You should help the user to order a cup of coffee.
-When you are using any Japanese words about origins of coffee, flaver, and other technique about coffee, please emphasize the word with markdown.
-"#;
-
-const NIHONGO_MOCK_SCENE_RESTAURANT_PROMPT: &str = r#"
-Your role is defined as follow:
-You are a waiter in a restaurant.
-You are helping the user to order a dish.
-You can recommend some dishes to the user.
-When you are using any Japanese words about food, vegetables, fruit, dishes, spice, flavor and drinks, please semphasize the word with markdown.
-"#;
-
-const NIHONGO_MOCK_SCENE_CLOTHES_SHOP_PROMPT: &str = r#"
-Your role is defined as follow:
-You are a shopping guide in a clothes shop.
-The clothes shop sells all kinds of clothes and shoes.
-You can guide the user per your understanding of the fashion in Japan.
-You can pretend the shop has a fitting room and let the user try the clothes or shoes.
-When you are using any Japanese words about clothes, style, and other fashion related words, please emphasize the word with markdown.
-"#;
-
-const NIHONGO_MOCK_SCENE_STREET_PROMPT: &str = r#"
-Your role is defined as follow:
-You are a passers-by on the street who have just met the user.
-You want to help the user know about the city, street and nearby.
-You can first ask the user about where the user is at and where the user want to go.
-When you are using any Japanese words about location, direction and other motion related words, please emphasize the word with markdown. 
-"#;
-
-const NIHONGO_MOCK_SCENE_SMALL_TALK_PROMPT: &str = r#"
-Your role is defined as follow:
-You are a passers-by who have just met the user.
-You and the user are going to have a random small talk.
-The topic can vary from weather to habbit.
-You can start by picking a random topic.
-"#;
+/// Minimum interval between two edits of a streaming answer, Telegram starts
+/// rejecting edits of the same message when they come in too fast.
+const STREAM_EDIT_INTERVAL: Duration = Duration::from_millis(1500);
 
-#[derive(Clone, Serialize, Deserialize)]
-enum TgBotPrompt {
-    Default,
-    NihongoTranslate,
-    NihongoExplain,
-    NihongoSceneMockCafe,
-    NihongoSceneMockRestaurant,
-    NihongoSceneMockClothesShop,
-    NihongoSceneMockStreet,
-    NihongoSceneMockSmallTalk,
-}
+/// Telegram keeps photos in several sizes, the largest one within this size
+/// is sent to the vision model.
+const MAX_IMAGE_SIZE: u32 = 1280;
 
-impl TgBotPrompt {
-    fn id(&self) -> &'static str {
-        match self {
-            TgBotPrompt::NihongoTranslate => "nihongo-translate",
-            TgBotPrompt::NihongoExplain => "nihongo-explain",
-            TgBotPrompt::NihongoSceneMockCafe => "nihongo-scene-mock-cafe",
-            TgBotPrompt::NihongoSceneMockRestaurant => "nihongo-scene-mock-restaurant",
-            TgBotPrompt::NihongoSceneMockClothesShop => "nihongo-scene-mock-clothes-shop",
-            TgBotPrompt::NihongoSceneMockStreet => "nihongo-scene-mock-street",
-            TgBotPrompt::NihongoSceneMockSmallTalk => "nihongo-scene-mock-small-talk",
-            _ => "default",
-        }
-    }
-
-    fn prompt(&self) -> String {
-        match self {
-            TgBotPrompt::NihongoTranslate => [DEFAULT_PROMPT, NIHONGO_TRANSLATE_PROMPT].join("\n"),
-            TgBotPrompt::NihongoExplain => [DEFAULT_PROMPT, NIHONGO_EXPLAIN_PROMPT].join("\n"),
-            TgBotPrompt::NihongoSceneMockCafe => [
-                DEFAULT_PROMPT,
-                NIHONGO_MOCK_SCENE_PROMPT,
-                NIHONGO_MOCK_SCENE_CAFE_PROMPT,
-            ]
-            .join("\n"),
-            TgBotPrompt::NihongoSceneMockRestaurant => [
-                DEFAULT_PROMPT,
-                NIHONGO_MOCK_SCENE_PROMPT,
-                NIHONGO_MOCK_SCENE_RESTAURANT_PROMPT,
-            ]
-            .join("\n"),
-            TgBotPrompt::NihongoSceneMockClothesShop => [
-                DEFAULT_PROMPT,
-                NIHONGO_MOCK_SCENE_PROMPT,
-                NIHONGO_MOCK_SCENE_CLOTHES_SHOP_PROMPT,
-            ]
-            .join("\n"),
-            TgBotPrompt::NihongoSceneMockStreet => [
-                DEFAULT_PROMPT,
-                NIHONGO_MOCK_SCENE_PROMPT,
-                NIHONGO_MOCK_SCENE_STREET_PROMPT,
-            ]
-            .join("\n"),
-            TgBotPrompt::NihongoSceneMockSmallTalk => [
-                DEFAULT_PROMPT,
-                NIHONGO_MOCK_SCENE_PROMPT,
-                NIHONGO_MOCK_SCENE_SMALL_TALK_PROMPT,
-            ]
-            .join("\n"),
-            _ => DEFAULT_PROMPT.to_owned(),
-        }
-    }
-}
+/// Buttons under every answer.
+const ANSWER_ACTIONS: [AnswerAction; 3] = [
+    AnswerAction::Regenerate,
+    AnswerAction::Continue,
+    AnswerAction::Shorter,
+];
 
-impl From<&str> for TgBotPrompt {
-    fn from(value: &str) -> Self {
-        match value {
-            "nihongo-translate" => TgBotPrompt::NihongoTranslate,
-            "nihongo-explain" => TgBotPrompt::NihongoExplain,
-            "nihongo-scene-mock-cafe" => TgBotPrompt::NihongoSceneMockCafe,
-            "nihongo-scene-mock-restaurant" => TgBotPrompt::NihongoSceneMockRestaurant,
-            "nihongo-scene-mock-clothes-shop" => TgBotPrompt::NihongoSceneMockClothesShop,
-            "nihongo-scene-mock-street" => TgBotPrompt::NihongoSceneMockStreet,
-            "nihongo-scene-mock-small-talk" => TgBotPrompt::NihongoSceneMockSmallTalk,
-            _ => TgBotPrompt::Default,
-        }
-    }
-}
+/// Personas answering inline queries, each of them gives a result.
+pub(crate) const INLINE_PERSONAS: [&str; 2] = ["nihongo-translate", DEFAULT_PERSONA];
 
-#[derive(Clone)]
-enum TgBotCommand {
-    Ask,
-    Nihongo,
-    Settings,
-    Help,
-}
+/// Seconds for Telegram to cache the results of an inline query, the answers
+/// are also kept in the store so they aren't generated again.
+const INLINE_CACHE_TIME: u32 = 300;
 
-#[derive(Clone, Debug)]
-enum TgBotInlineButton {
-    // nihongo
-    NihongoTranslate,
-    NihongoExplain,
-    NihongoSceneMock,
-    NihongoSceneMockRestaurant,
-    NihongoSceneMockCafe,
-    NihongoSceneMockClothesShop,
-    NihongoSceneMockStreet,
-    NihongoSceneMockSmallTalk,
-    NihongoSceneMockGoBack,
-    // settings
-    SettingsLMGPT35Turbo,
-    SettingsLMGPT35Turbo16K,
-    SettingsLMGPT4,
-}
+/// Notice for buttons of menus which no longer resolve, e.g. sent by an older
+/// version of the bot.
+const MENU_EXPIRED: &str = "This menu has expired, please open it again.";
 
-impl TgBotInlineButton {
-    fn id(&self) -> String {
-        // nihongo
-        match self {
-            TgBotInlineButton::NihongoTranslate => "NihongoTranslate",
-            TgBotInlineButton::NihongoExplain => "NihongoExplain",
-            TgBotInlineButton::NihongoSceneMock => "NihongoSceneMock",
-            TgBotInlineButton::NihongoSceneMockRestaurant => "NihongoSceneMockRestaurant",
-            TgBotInlineButton::NihongoSceneMockCafe => "NihongoSceneMockCafe",
-            TgBotInlineButton::NihongoSceneMockClothesShop => "NihongoSceneMockClothesShop",
-            TgBotInlineButton::NihongoSceneMockStreet => "NihongoSceneMockStreet",
-            TgBotInlineButton::NihongoSceneMockSmallTalk => "NihongoSceneMockSmallTalk",
-            TgBotInlineButton::NihongoSceneMockGoBack => "NihongoSceneMockGoBack",
-            // settings
-            TgBotInlineButton::SettingsLMGPT35Turbo => "SettingsLMGPT35Turbo",
-            TgBotInlineButton::SettingsLMGPT35Turbo16K => "SettingsLMGPT35Turbo16K",
-            TgBotInlineButton::SettingsLMGPT4 => "SettingsLMGPT4",
-        }
-        .to_owned()
-    }
-
-    fn title(&self) -> String {
-        match self {
-            TgBotInlineButton::NihongoTranslate => "翻訳",
-            TgBotInlineButton::NihongoExplain => "説明",
-            TgBotInlineButton::NihongoSceneMock => "模擬会話",
-            TgBotInlineButton::NihongoSceneMockRestaurant => "レストラン",
-            TgBotInlineButton::NihongoSceneMockCafe => "カフェ",
-            TgBotInlineButton::NihongoSceneMockClothesShop => "服屋",
-            TgBotInlineButton::NihongoSceneMockStreet => "街",
-            TgBotInlineButton::NihongoSceneMockSmallTalk => "自由",
-            TgBotInlineButton::NihongoSceneMockGoBack => "戻る",
-            TgBotInlineButton::SettingsLMGPT35Turbo => "gpt3.5-turbo",
-            TgBotInlineButton::SettingsLMGPT35Turbo16K => "gpt3.5-turbo-16k",
-            TgBotInlineButton::SettingsLMGPT4 => "gpt4",
-        }
-        .to_string()
-    }
-}
+/// Inline results show the beginning of the answers.
+const INLINE_DESCRIPTION_LENGTH: usize = 100;
 
-impl TryFrom<&str> for TgBotInlineButton {
-    type Error = anyhow::Error;
-
-    fn try_from(value: &str) -> anyhow::Result<Self> {
-        match value {
-            // nihongo
-            "NihongoTranslate" => Ok(Self::NihongoTranslate),
-            "NihongoExplain" => Ok(Self::NihongoExplain),
-            "NihongoSceneMock" => Ok(Self::NihongoSceneMock),
-            "NihongoSceneMockCafe" => Ok(Self::NihongoSceneMockCafe),
-            "NihongoSceneMockRestaurant" => Ok(Self::NihongoSceneMockRestaurant),
-            "NihongoSceneMockClothesShop" => Ok(Self::NihongoSceneMockClothesShop),
-            "NihongoSceneMockStreet" => Ok(Self::NihongoSceneMockStreet),
-            "NihongoSceneMockSmallTalk" => Ok(Self::NihongoSceneMockSmallTalk),
-            "NihongoSceneMockGoBack" => Ok(Self::NihongoSceneMockGoBack),
-            // settings
-            "SettingsLMGPT35Turbo" => Ok(Self::SettingsLMGPT35Turbo),
-            "SettingsLMGPT35Turbo16K" => Ok(Self::SettingsLMGPT35Turbo16K),
-            "SettingsLMGPT4" => Ok(Self::SettingsLMGPT4),
-            // unknown
-            unknown => anyhow::bail!("unknown id: {}", unknown),
-        }
-    }
-}
+const DEFAULT_BOT_NAME: &str = "Cheese";
 
-impl From<TgBotInlineButton> for InlineKeyboardButton {
-    fn from(tg_kb: TgBotInlineButton) -> Self {
-        InlineKeyboardButton::new(
-            tg_kb.title(),
-            tg_flows::InlineKeyboardButtonKind::CallbackData(tg_kb.id()),
-        )
-    }
-}
+const DEFAULT_CREATOR: &str = "Chase Zhang";
 
-impl TgBotCommand {
-    fn root_commands() -> Vec<TgBotCommand> {
-        vec![
-            TgBotCommand::Ask,
-            TgBotCommand::Nihongo,
-            TgBotCommand::Settings,
-            TgBotCommand::Help,
-        ]
-    }
+const DEFAULT_HELP_MSG: &str = "Hi! I'm you jotting pal.";
+
+#[derive(Clone, Serialize, Deserialize)]
+struct TgBotContext {
+    id: String,
+    prompt: String,
+    /// Ids of the documents shared in the conversation.
+    #[serde(default)]
+    documents: Vec<String>,
 }
 
-impl From<TgBotCommand> for BotCommand {
-    fn from(val: TgBotCommand) -> Self {
-        match val {
-            TgBotCommand::Ask => BotCommand::new("ask", "ask any questions"),
-            TgBotCommand::Nihongo => {
-                BotCommand::new("nihongo", "learn japanese by sentences and questions")
-            }
-            TgBotCommand::Settings => BotCommand::new("settings", "adjust settings of the bot"),
-            TgBotCommand::Help => BotCommand::new("help", "show help messages"),
-        }
-    }
+/// The chat and the user a completion is counted for, and the model counted.
+#[derive(Clone, Copy)]
+struct UsageTarget<'a> {
+    chat_id: ChatId,
+    user_id: Option<UserId>,
+    model: &'a str,
 }
 
-impl fmt::Display for TgBotCommand {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let cmd: BotCommand = self.clone().into();
-        write!(f, "/{} {}", cmd.command, cmd.description)
-    }
+#[derive(Serialize, Deserialize)]
+struct InlineAnswer {
+    persona: String,
+    answer: String,
 }
 
-#[derive(Clone, Serialize, Deserialize)]
-struct TgBotContext {
-    id: String,
-    prompt: TgBotPrompt,
+#[derive(Serialize, Deserialize)]
+struct InlineCache {
+    query: String,
+    #[serde(default)]
+    model: String,
+    answers: Vec<InlineAnswer>,
 }
 
 pub struct TgBot {
     tg: Telegram,
+    telegram_token: String,
     openai: OpenAIFlows,
+    personas: PersonaRegistry,
+    menus: MenuTree,
+    access: AccessControl,
+    quotas: Quotas,
+    admins: Vec<u64>,
+    openai_api_key: Option<String>,
+    streaming: bool,
     help_msg: String,
 }
 
@@ -323,10 +113,40 @@ impl Default for TgBot {
         let mut openai = OpenAIFlows::new();
         openai.set_retry_times(3);
 
+        let mut personas = PersonaRegistry::load();
+        if let Some(prompt) = SettingsScope::Deployment
+            .get_system_prompt()
+            .or_else(|| std::env::var("system_prompt").ok())
+        {
+            personas.set_base_prompt(prompt);
+        }
+        personas.set_variable(
+            "bot_name",
+            std::env::var("bot_name").unwrap_or(DEFAULT_BOT_NAME.into()),
+        );
+        personas.set_variable(
+            "creator",
+            std::env::var("creator").unwrap_or(DEFAULT_CREATOR.into()),
+        );
+
         Self {
-            tg: Telegram::new(telegram_token),
+            tg: Telegram::new(telegram_token.clone()),
+            telegram_token,
             openai,
-            help_msg: "Hi! I'm you jotting pal.".into(),
+            personas,
+            menus: MenuTree::default(),
+            access: AccessControl::from_env(),
+            quotas: Quotas::from_env(),
+            admins: std::env::var("admin_users")
+                .unwrap_or_default()
+                .split(',')
+                .filter_map(|id| id.trim().parse().ok())
+                .collect(),
+            openai_api_key: std::env::var("openai_api_key").ok(),
+            streaming: std::env::var("streaming")
+                .map(|v| v == "true")
+                .unwrap_or(false),
+            help_msg: std::env::var("help_msg").unwrap_or(DEFAULT_HELP_MSG.into()),
         }
     }
 }
@@ -337,20 +157,213 @@ impl TgBot {
         match update.kind {
             UpdateKind::Message(msg) => {
                 let chat_id = msg.chat.id;
-                match msg.text() {
-                    Some(_) if msg.reply_to_message().is_some() => self.handle_ask(&msg).await,
-                    Some(text) if text.starts_with("/ask") => self.handle_ask(&msg).await,
-                    Some(text) if text.starts_with("/nihongo") => self.handle_nihongo(&msg, false),
-                    Some(text) if text.starts_with("/settings") => self.handle_settings(&msg),
-                    _ => self.show_help_message(chat_id),
+                let username = self.get_bot_username();
+                let text = msg.text().or_else(|| msg.caption()).unwrap_or_default();
+                let has_voice = msg.voice().is_some() || msg.audio().is_some();
+                let has_file = msg.photo().is_some() || msg.document().is_some();
+                let parsed = command::parse_command(text);
+                if let Some(ref parsed) = parsed {
+                    if !parsed.is_for(username.as_deref()) {
+                        log::info!("command for another bot: {}", text);
+                        return Ok(());
+                    }
+                }
+
+                let is_group = msg.chat.is_group() || msg.chat.is_supergroup();
+                let question = if parsed.is_some() || (text.is_empty() && !has_file && !has_voice) {
+                    None
+                } else if is_group {
+                    self.get_group_question(&msg, text, username.as_deref())
+                } else if has_file || has_voice {
+                    Some(text.to_owned())
+                } else {
+                    msg.reply_to_message().map(|_| text.to_owned())
+                };
+                if is_group && parsed.is_none() && question.is_none() {
+                    // unrelated chatter in the group
+                    return Ok(());
+                }
+                if let Some(ref parsed) = parsed {
+                    // a group may have other bots, whose commands such as a bare
+                    // `/start` aren't known to be for this one
+                    let unknown = match parsed.command {
+                        None => true,
+                        Some(TgBotCommand::Start) => parsed.username.is_none(),
+                        Some(_) => false,
+                    };
+                    if is_group && unknown {
+                        log::info!("ignore command in group: {}", text);
+                        return Ok(());
+                    }
+                }
+
+                if let Some(ParsedCommand {
+                    command: Some(TgBotCommand::Invite | TgBotCommand::Start),
+                    args,
+                    ..
+                }) = parsed
+                {
+                    if !args.is_empty() {
+                        return self.handle_invite(&msg, args).map(|_| ());
+                    }
+                }
+                let user_id = msg.from().map(|u| u.id);
+                if !self.is_admin(user_id) && !self.access.is_allowed(chat_id, user_id) {
+                    log::info!("access denied, chat id: {}", chat_id);
+                    return self.show_access_denied(&msg).map(|_| ());
+                }
+                usage::record_chat(chat_id);
+
+                let command = parsed.as_ref().and_then(|parsed| parsed.command);
+                let args = parsed
+                    .as_ref()
+                    .map(|parsed| parsed.args)
+                    .unwrap_or_default();
+                let question = match question {
+                    Some(caption) if has_voice => {
+                        // the question is answered after the transcription, check both quotas first
+                        let lm = settings::resolve_language_model(chat_id, user_id);
+                        for model in [lm.as_str(), openaiext::TRANSCRIPTION_MODEL] {
+                            if let Err(exceeded) = self.quotas.check(chat_id, user_id, model) {
+                                log::info!("quota exceeded, chat id: {}", chat_id);
+                                return self
+                                    .tg
+                                    .reply_to_message(&msg, exceeded.to_string())
+                                    .map(|_| ());
+                            }
+                        }
+                        match self.transcribe_message(&msg, &caption) {
+                            Ok(question) => Some(question),
+                            Err(e) => {
+                                log::error!("failed to transcribe: {}", e);
+                                return self
+                                    .tg
+                                    .reply_to_message(
+                                        &msg,
+                                        "Sorry, I couldn't hear the voice message.",
+                                    )
+                                    .map(|_| ());
+                            }
+                        }
+                    }
+                    question => question,
+                };
+                match command {
+                    Some(TgBotCommand::Admin) if self.is_admin(user_id) => {
+                        self.handle_admin(&msg, args)
+                    }
+                    Some(TgBotCommand::Reset) => self.handle_reset(&msg),
+                    Some(TgBotCommand::New) => self.handle_new(&msg),
+                    Some(TgBotCommand::Usage) => self.handle_usage(&msg),
+                    Some(TgBotCommand::Ask) => {
+                        self.handle_ask(&msg, Some(args).filter(|q| !q.is_empty() || has_file))
+                            .await
+                    }
+                    Some(TgBotCommand::Nihongo) => self.show_menu(&msg, "nihongo", None, false),
+                    Some(TgBotCommand::Settings) => {
+                        self.show_menu(&msg, "settings", user_id, false)
+                    }
+                    Some(_) => self.show_help_message(chat_id),
+                    None => match question {
+                        Some(ref question) => self.handle_ask(&msg, Some(question)).await,
+                        None => self.show_help_message(chat_id),
+                    },
                 }
                 .map(|_| ())
             }
-            UpdateKind::CallbackQuery(cq) => self.handle_callback_query(&cq).map(|_| ()),
+            UpdateKind::CallbackQuery(cq) => {
+                let allowed = self.is_admin(Some(cq.from.id))
+                    || cq
+                        .message
+                        .as_ref()
+                        .map(|msg| self.access.is_allowed(msg.chat.id, Some(cq.from.id)))
+                        .unwrap_or(false);
+                if !allowed {
+                    log::info!("access denied, user id: {}", cq.from.id);
+                    return self
+                        .tg
+                        .answer_callback_query(
+                            &cq.id,
+                            "Sorry, you are not allowed to use this bot.",
+                        )
+                        .map(|_| ());
+                }
+                if let (Some(data), Some(msg)) = (&cq.data, &cq.message) {
+                    if let Some(action) = AnswerAction::from_data(data) {
+                        return self.handle_answer_action(&cq, msg, action).await;
+                    }
+                }
+                self.handle_callback_query(&cq)
+            }
+            UpdateKind::EditedMessage(msg) => self.handle_edited_message(&msg).await,
+            UpdateKind::InlineQuery(query) => self.handle_inline_query(&query).await.map(|_| ()),
             _ => Ok(()),
         }
     }
 
+    /// Find out the question in a group message, which depends on the group
+    /// mode of the chat. Returns `None` when the message isn't for the bot.
+    fn get_group_question(
+        &self,
+        msg: &Message,
+        text: &str,
+        username: Option<&str>,
+    ) -> Option<String> {
+        if settings::resolve_group_mode(msg.chat.id) == settings::GROUP_MODE_ALL {
+            return Some(text.to_owned());
+        }
+
+        let replied_to_bot = msg
+            .reply_to_message()
+            .and_then(|reply| reply.from())
+            .map(|user| user.is_bot && (username.is_none() || user.username.as_deref() == username))
+            .unwrap_or(false);
+        if replied_to_bot {
+            return Some(text.to_owned());
+        }
+
+        let mention = format!("@{}", username?).to_ascii_lowercase();
+        let lower = text.to_ascii_lowercase();
+        // `@bot` mustn't match the start of another username such as `@bot2`
+        let pos = lower
+            .match_indices(&mention)
+            .map(|(pos, _)| pos)
+            .find(|&pos| {
+                !lower[pos + mention.len()..]
+                    .starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_')
+            })?;
+        let question = format!("{}{}", &text[..pos], &text[pos + mention.len()..]);
+        Some(question.trim().to_owned())
+    }
+
+    /// The username of the bot, from `bot_username` or asked from Telegram
+    /// once and kept in the store.
+    fn get_bot_username(&self) -> Option<String> {
+        if let Ok(username) = std::env::var("bot_username") {
+            return Some(username);
+        }
+        if let Some(username) =
+            store_flows::get("bot.username").and_then(|v| v.as_str().map(String::from))
+        {
+            return Some(username);
+        }
+        match self.tg.get_me().map(|me| me.username.clone()) {
+            Ok(Some(username)) => {
+                store_flows::set(
+                    "bot.username",
+                    serde_json::Value::String(username.clone()),
+                    None,
+                );
+                Some(username)
+            }
+            Ok(None) => None,
+            Err(e) => {
+                log::error!("failed to get the bot username: {}", e);
+                None
+            }
+        }
+    }
+
     fn set_typing(&self, chat_id: ChatId) -> anyhow::Result<tg_flows::Message> {
         self.tg.send_chat_action(chat_id, "typing".to_string())
     }
@@ -370,76 +383,256 @@ impl TgBot {
         )
     }
 
+    fn show_access_denied(&self, msg: &Message) -> anyhow::Result<tg_flows::Message> {
+        let text = if self.access.has_invite_codes() {
+            "Sorry, you are not allowed to use this bot. \
+             If you have an invite code, please send it with /invite <code>."
+        } else {
+            "Sorry, you are not allowed to use this bot."
+        };
+        self.tg.reply_to_message(msg, text)
+    }
+
+    /// Redeem an invite code, in a group it allows everyone in the group,
+    /// otherwise it allows the sender.
+    fn handle_invite(&self, msg: &Message, code: &str) -> anyhow::Result<tg_flows::Message> {
+        let scope = match msg.from() {
+            Some(user) if msg.chat.is_private() => AccessScope::User(user.id),
+            _ => AccessScope::Chat(msg.chat.id),
+        };
+        log::info!("redeem invite code for {:?}", scope);
+
+        if self.access.redeem(code, scope) {
+            self.tg.reply_to_message(
+                msg,
+                format!(
+                    "Welcome! {} Send /help to see what I can do.",
+                    self.help_msg
+                ),
+            )
+        } else {
+            self.tg
+                .reply_to_message(msg, "Sorry, the invite code is invalid or has been used.")
+        }
+    }
+
     pub fn set_bot_commands(&self) -> anyhow::Result<bool> {
+        for admin in self.admins.iter() {
+            let scope = BotCommandScope::Chat {
+                chat_id: Recipient::Id(ChatId(*admin as i64)),
+            };
+            if let Err(e) = self
+                .tg
+                .set_my_commands_with_scope(TgBotCommand::admin_commands(), scope)
+            {
+                log::error!("failed to set admin commands for {}: {}", admin, e);
+            }
+        }
         self.tg.set_my_commands(TgBotCommand::root_commands())
     }
 
-    async fn handle_ask(&self, msg: &Message) -> anyhow::Result<tg_flows::Message> {
-        let text = msg.text().unwrap();
-        log::info!("handle ask: {}", text);
+    fn is_admin(&self, user_id: Option<UserId>) -> bool {
+        user_id
+            .map(|id| self.admins.contains(&id.0))
+            .unwrap_or(false)
+    }
+
+    fn handle_admin(&self, msg: &Message, args: &str) -> anyhow::Result<tg_flows::Message> {
+        let (subcommand, rest) = args
+            .split_once(char::is_whitespace)
+            .map(|(subcommand, rest)| (subcommand, rest.trim()))
+            .unwrap_or((args, ""));
+        log::info!("admin command: {} {}", subcommand, rest);
+
+        match (subcommand, rest) {
+            ("stats", _) => self.handle_admin_stats(msg),
+            ("setmodel", lm) if settings::LANGUAGE_MODELS.contains(&lm) => {
+                SettingsScope::Deployment.set_language_model(lm);
+                self.tg
+                    .reply_to_message(msg, format!("Default language model: {}", lm))
+            }
+            ("allow", id) | ("ban", id) if id.parse::<i64>().is_ok() => {
+                let id: i64 = id.parse()?;
+                // user ids are positive while group chat ids are negative
+                let scope = if id > 0 {
+                    AccessScope::User(UserId(id as u64))
+                } else {
+                    AccessScope::Chat(ChatId(id))
+                };
+                let allowed = subcommand == "allow";
+                scope.set(allowed);
+                self.tg.reply_to_message(
+                    msg,
+                    format!(
+                        "{:?} is {}",
+                        scope,
+                        if allowed { "allowed" } else { "banned" }
+                    ),
+                )
+            }
+            ("broadcast", text) if !text.is_empty() => {
+                let chats = usage::known_chats();
+                let sent = chats
+                    .iter()
+                    .filter(|id| {
+                        self.tg
+                            .send_message(ChatId(**id), text)
+                            .map_err(|e| log::warn!("failed to broadcast to {}: {}", id, e))
+                            .is_ok()
+                    })
+                    .count();
+                self.tg.reply_to_message(
+                    msg,
+                    format!("Broadcasted to {} of {} chats.", sent, chats.len()),
+                )
+            }
+            ("prompt", "show") => self
+                .tg
+                .reply_to_message(msg, self.personas.prompt(DEFAULT_PERSONA)),
+            ("prompt", rest) => match rest.split_once(char::is_whitespace) {
+                Some(("set", prompt)) if !prompt.trim().is_empty() => {
+                    SettingsScope::Deployment.set_system_prompt(prompt.trim());
+                    self.tg
+                        .reply_to_message(msg, "The system prompt is updated.")
+                }
+                _ => self.show_admin_usage(msg),
+            },
+            _ => self.show_admin_usage(msg),
+        }
+    }
 
-        if msg.reply_to_message().is_some() || text.starts_with("/ask ") {
-            let question = text.strip_prefix("/ask ").unwrap_or(text);
+    fn show_admin_usage(&self, msg: &Message) -> anyhow::Result<tg_flows::Message> {
+        self.tg.reply_to_message(
+            msg,
+            format!(
+                "Usage: /admin {}\nLanguage models: {}",
+                BotCommand::from(TgBotCommand::Admin).description,
+                settings::LANGUAGE_MODELS.join(", ")
+            ),
+        )
+    }
+
+    fn handle_admin_stats(&self, msg: &Message) -> anyhow::Result<tg_flows::Message> {
+        let now = Utc::now();
+        let lines: Vec<String> = settings::LANGUAGE_MODELS
+            .iter()
+            .map(|lm| {
+                let daily = usage::get_usage(UsageScope::Deployment, lm, UsageWindow::Daily, now);
+                let monthly =
+                    usage::get_usage(UsageScope::Deployment, lm, UsageWindow::Monthly, now);
+                format!(
+                    "{}\n  today: {} requests, {} tokens\n  this month: {} requests, {} tokens",
+                    lm, daily.requests, daily.tokens, monthly.requests, monthly.tokens
+                )
+            })
+            .collect();
+
+        self.tg.reply_to_message(
+            msg,
+            format!(
+                "Chats: {}\nDefault language model: {}\n{}",
+                usage::known_chats().len(),
+                settings::default_language_model(),
+                lines.join("\n")
+            ),
+        )
+    }
+
+    async fn handle_ask(
+        &self,
+        msg: &Message,
+        question: Option<&str>,
+    ) -> anyhow::Result<tg_flows::Message> {
+        log::info!("handle ask: {:?}", question);
+
+        if let Some(question) = question {
+            let user_id = msg.from().map(|u| u.id);
+            let lm = settings::resolve_language_model(msg.chat.id, user_id);
+            log::info!("language model: {}", lm);
+
+            let images = TgBot::get_message_images(msg);
+            if !images.is_empty() && self.openai_api_key.is_none() {
+                log::warn!("can't read images without openai_api_key");
+                return self
+                    .tg
+                    .reply_to_message(msg, "Sorry, I can't read images yet.");
+            }
+            let has_images = !images.is_empty()
+                || Conversation::load(&TgBot::get_message_context(msg).id).has_images();
+            let quota_model = TgBot::quota_model(&lm, has_images);
+            if let Err(exceeded) = self.quotas.check(msg.chat.id, user_id, &quota_model) {
+                log::info!("quota exceeded, chat id: {}", msg.chat.id);
+                return self.tg.reply_to_message(msg, exceeded.to_string());
+            }
+
+            let documents = match self.read_message_documents(msg) {
+                Ok(documents) => documents,
+                Err(e) => {
+                    log::warn!("failed to read documents: {}", e);
+                    return self.tg.reply_to_message(
+                        msg,
+                        format!("Sorry, I can't read the document, {}.", e),
+                    );
+                }
+            };
+            let question = if question.is_empty() && !documents.is_empty() {
+                "Summarize the document briefly."
+            } else {
+                question
+            };
 
             log::info!("reply to message: {}", msg.id);
             let placeholder = self.tg.reply_to_message(msg, "typing...")?;
+            store_flows::set(
+                &TgBot::get_answer_ptr(msg),
+                serde_json::json!(placeholder.id.0),
+                None,
+            );
 
             log::info!("set to typing, chat id: {}", msg.chat.id);
             // ignore callback result
             let _ = self.set_typing(msg.chat.id);
 
             let root = TgBot::get_root_message(msg);
-            let root_ptr = TgBot::get_message_ptr(root);
-            let chat_ctx = store_flows::get(&root_ptr)
-                .and_then(|v| serde_json::from_value(v).ok())
-                .unwrap_or(TgBotContext {
-                    id: root_ptr,
-                    prompt: TgBotPrompt::Default,
-                });
+            let mut chat_ctx = TgBot::get_message_context(msg);
+            chat_ctx.documents.extend(documents);
 
-            TgBot::set_message_context(&placeholder, &serde_json::to_value(&chat_ctx).unwrap());
+            let ctx = serde_json::to_value(&chat_ctx).unwrap();
+            TgBot::set_message_context(&placeholder, &ctx);
+            if let Some(reply) = msg.reply_to_message() {
+                // the message replied to isn't nested in the placeholder, e.g. a photo of the user
+                TgBot::set_message_context(reply, &ctx);
+            }
 
             let chat_ptr = chat_ctx.id.as_str();
-            let chat_ctx_id = format!("ctx--{}", chat_ptr);
             log::info!(
-                "placeholder: {} root: {}, chat_ctx_id: {}, chat_prompt: {}, chat_ctx: {}",
+                "placeholder: {} root: {}, chat_ptr: {}, chat_prompt: {}",
                 placeholder.id,
                 root.id,
-                chat_ctx_id,
-                chat_ctx.prompt.id(),
-                store_flows::get(&chat_ctx_id).unwrap_or("None".into())
+                chat_ptr,
+                chat_ctx.prompt,
             );
 
-            let mut copt = ChatOptions::default();
-
-            let lm = store_flows::get("settings.language.model")
-                .unwrap_or(serde_json::Value::String("gpt4".to_string()));
-
-            match lm.as_str() {
-                Some("gpt4") => copt.model = ChatModel::GPT4,
-                Some("gpt3.5-turbo") => copt.model = ChatModel::GPT35Turbo,
-                _ => copt.model = ChatModel::GPT35Turbo16K,
-            }
-
-            let prompt = chat_ctx.prompt.prompt();
-            copt.restart = false;
-            copt.system_prompt = Some(prompt.as_str());
-
-            match self
-                .openai
-                .chat_completion(&chat_ctx_id, question, &copt)
-                .await
-            {
-                Ok(resp) => {
-                    self.tg
-                        .edit_message_text_ext(msg.chat.id, placeholder.id, resp.choice, None)
-                }
-                Err(_) => self.tg.edit_message_text(
-                    msg.chat.id,
-                    placeholder.id,
-                    "Sorry, an error has occured. Please try again later.",
-                ),
+            let prompt = self.get_prompt(&chat_ctx, &lm, question);
+            let mut conversation = Conversation::load(chat_ptr);
+            conversation.persona = chat_ctx.prompt.clone();
+            conversation
+                .turns
+                .push(Turn::user(question, images, msg.id.0));
+            if self.streaming && self.openai_api_key.is_none() {
+                log::warn!("streaming is enabled but openai_api_key is not set");
             }
+            self.answer_conversation(
+                msg.chat.id,
+                user_id,
+                placeholder.id,
+                &chat_ctx,
+                &mut conversation,
+                &lm,
+                &prompt,
+                None,
+            )
+            .await
         } else {
             log::info!("force reply: {}", msg.chat.id);
             self.tg.send_message_ext(
@@ -451,197 +644,1018 @@ impl TgBot {
         }
     }
 
-    fn handle_nihongo(&self, msg: &Message, edit: bool) -> anyhow::Result<tg_flows::Message> {
-        let keyboard = tg_flows::InlineKeyboardMarkup::default()
-            .append_row(vec![
-                TgBotInlineButton::NihongoTranslate.into(),
-                TgBotInlineButton::NihongoExplain.into(),
-            ])
-            .append_row(vec![TgBotInlineButton::NihongoSceneMock.into()]);
+    /// Answer an inline query with a result of each of `INLINE_PERSONAS`,
+    /// answers of the same query and model are generated only once.
+    async fn handle_inline_query(&self, iq: &InlineQuery) -> anyhow::Result<bool> {
+        let query = iq.query.trim();
+        let user_id = iq.from.id;
+        // the private chat with a user has the same id as the user
+        let chat_id = ChatId(user_id.0 as i64);
+        if query.is_empty() {
+            return self
+                .tg
+                .answer_inline_query(&iq.id, vec![], INLINE_CACHE_TIME);
+        }
+        if !self.is_admin(Some(user_id)) && !self.access.is_allowed(chat_id, Some(user_id)) {
+            log::info!("access denied, user id: {}", user_id);
+            return self
+                .tg
+                .answer_inline_query(&iq.id, vec![], INLINE_CACHE_TIME);
+        }
 
-        if edit {
-            self.tg.edit_message_text_ext(
+        let lm = settings::resolve_language_model(chat_id, Some(user_id));
+        let mut hasher = DefaultHasher::new();
+        (&lm, query).hash(&mut hasher);
+        let cache_key = format!("inline--{:016x}", hasher.finish());
+        let cached = store_flows::get(&cache_key)
+            .and_then(|v| serde_json::from_value::<InlineCache>(v).ok())
+            .filter(|cache| cache.query == query && cache.model == lm);
+
+        let answers = match cached {
+            Some(cache) => {
+                log::info!("inline query is cached: {}", cache_key);
+                cache.answers
+            }
+            None => {
+                if let Err(exceeded) = self.quotas.check(chat_id, Some(user_id), &lm) {
+                    log::info!("quota exceeded, user id: {}: {}", user_id, exceeded);
+                    return self
+                        .tg
+                        .answer_inline_query(&iq.id, vec![], INLINE_CACHE_TIME);
+                }
+
+                let target = UsageTarget {
+                    chat_id,
+                    user_id: Some(user_id),
+                    model: &lm,
+                };
+                // the personas are answered concurrently to keep within the deadline of the query
+                let [first, second] = INLINE_PERSONAS
+                    .map(|persona_id| self.answer_inline(target, &lm, persona_id, query));
+                let (first, second) = tokio::join!(first, second);
+
+                let cache = InlineCache {
+                    query: query.to_owned(),
+                    model: lm.clone(),
+                    answers: vec![first?, second?],
+                };
+                store_flows::set(&cache_key, serde_json::to_value(&cache)?, None);
+                cache.answers
+            }
+        };
+
+        let mode = settings::parse_mode();
+        let results = answers
+            .iter()
+            .enumerate()
+            .map(|(i, answer)| {
+                let content = if mode == ParseMode::Entities {
+                    let (text, entities) =
+                        markdown::split_entities(&answer.answer, MESSAGE_LENGTH_LIMIT)
+                            .into_iter()
+                            .next()
+                            .unwrap_or_default();
+                    serde_json::json!({
+                        "message_text": text,
+                        "entities": entities,
+                    })
+                } else {
+                    let text = mode
+                        .split(&answer.answer, MESSAGE_LENGTH_LIMIT)
+                        .into_iter()
+                        .next()
+                        .unwrap_or_default();
+                    serde_json::json!({
+                        "message_text": text,
+                        "parse_mode": mode.name(),
+                    })
+                };
+                serde_json::json!({
+                    "type": "article",
+                    "id": i.to_string(),
+                    "title": self.personas.resolve(&answer.persona).title,
+                    "description": answer
+                        .answer
+                        .chars()
+                        .take(INLINE_DESCRIPTION_LENGTH)
+                        .collect::<String>(),
+                    "input_message_content": content,
+                })
+            })
+            .collect();
+        self.tg
+            .answer_inline_query(&iq.id, results, INLINE_CACHE_TIME)
+    }
+
+    fn handle_usage(&self, msg: &Message) -> anyhow::Result<tg_flows::Message> {
+        let user_id = match msg.from() {
+            Some(user) => user.id,
+            None => bail!("can't show usage without a user"),
+        };
+
+        let now = Utc::now();
+        let scope = UsageScope::User(user_id);
+        let mut lines = vec![];
+        for lm in settings::LANGUAGE_MODELS {
+            let limits = self.quotas.limits(scope, lm);
+            let daily = usage::get_usage(scope, lm, UsageWindow::Daily, now);
+            let monthly = usage::get_usage(scope, lm, UsageWindow::Monthly, now);
+            let limit = |limit: Option<u64>| limit.map(|l| format!("/{}", l)).unwrap_or_default();
+            lines.push(format!(
+                "{}\n  today: {}{} requests, {}{} tokens\n  this month: {}{} requests, {}{} tokens",
+                lm,
+                daily.requests,
+                limit(limits.daily_requests),
+                daily.tokens,
+                limit(limits.daily_tokens),
+                monthly.requests,
+                limit(limits.monthly_requests),
+                monthly.tokens,
+                limit(limits.monthly_tokens),
+            ));
+        }
+
+        self.tg.reply_to_message(
+            msg,
+            format!(
+                "Your usage, tokens are estimated:\n{}\nDaily usage is reset at {}.",
+                lines.join("\n"),
+                UsageWindow::Daily
+                    .reset_at(now)
+                    .format("%Y-%m-%d %H:%M UTC")
+            ),
+        )
+    }
+
+    /// Restart the conversation of the reply chain, the chain keeps its
+    /// persona but continues with a new context.
+    fn handle_reset(&self, msg: &Message) -> anyhow::Result<tg_flows::Message> {
+        if msg.reply_to_message().is_none() {
+            return self.tg.send_message_ext(
                 msg.chat.id,
-                msg.id,
-                "どのようにおてつだいでくますか？",
-                Some(tg_flows::ReplyMarkup::InlineKeyboard(keyboard)),
+                Some(&msg.id),
+                "Reply /reset to a message of the conversation you want to restart.",
+                None,
+            );
+        }
+
+        let chat_ctx = TgBot::get_message_context(msg);
+        log::info!("reset conversation: {}", chat_ctx.id);
+        self.tg
+            .send_message_ext(
+                msg.chat.id,
+                Some(&msg.id),
+                "The conversation is restarted, how can I help you?",
+                Some(ReplyMarkup::ForceReply(ForceReply::default())),
             )
+            .map(|reply| self.init_message_prompt(reply, &chat_ctx.prompt))
+    }
+
+    /// Start a new conversation which is not replying to anything, with the
+    /// persona of the conversation being replied to.
+    fn handle_new(&self, msg: &Message) -> anyhow::Result<tg_flows::Message> {
+        let persona_id = match msg.reply_to_message() {
+            Some(_) => TgBot::get_message_context(msg).prompt,
+            None => DEFAULT_PERSONA.to_owned(),
+        };
+        log::info!("new conversation, persona: {}", persona_id);
+
+        let persona = self.personas.resolve(&persona_id);
+        let entry = if persona.entry.is_empty() {
+            "A new conversation is started, how can I help you?"
         } else {
-            self.tg.send_message_ext(
+            persona.entry.as_str()
+        };
+        self.tg
+            .send_message_ext(
                 msg.chat.id,
-                Some(&msg.id),
-                "どのようにおてつだいでくますか？",
-                Some(tg_flows::ReplyMarkup::InlineKeyboard(keyboard)),
+                None,
+                entry,
+                Some(ReplyMarkup::ForceReply(ForceReply::default())),
             )
+            .map(|reply| self.init_message_prompt(reply, &persona.id))
+    }
+
+    /// Send a long answer as the placeholder followed by a chain of replies,
+    /// every part points to the same context so the conversation can continue
+    /// from any of them.
+    fn reply_answer(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+        chat_ctx: &TgBotContext,
+        answer: &str,
+    ) -> anyhow::Result<Vec<tg_flows::Message>> {
+        let ctx = serde_json::to_value(chat_ctx)?;
+        // parts are formatted by entities, or converted to the parse mode
+        let mode = settings::parse_mode();
+        let parts: Vec<(String, Option<Vec<MessageEntity>>)> = if mode == ParseMode::Entities {
+            markdown::split_entities(answer, MESSAGE_LENGTH_LIMIT)
+                .into_iter()
+                .map(|(text, entities)| (text, Some(entities)))
+                .collect()
+        } else {
+            mode.split(answer, MESSAGE_LENGTH_LIMIT)
+                .into_iter()
+                .map(|part| (part, None))
+                .collect()
+        };
+        let count = parts.len();
+
+        let mut messages: Vec<Message> = vec![];
+        for (i, (part, entities)) in parts.into_iter().enumerate() {
+            // the actions are under the last part of the answer
+            let markup = if i + 1 == count {
+                Some(ReplyMarkup::InlineKeyboard(AnswerAction::keyboard(
+                    &ANSWER_ACTIONS,
+                )))
+            } else {
+                None
+            };
+            let msg = match (messages.last(), entities) {
+                (None, Some(entities)) => self
+                    .tg
+                    .edit_message_text_entities(chat_id, message_id, part, entities, markup)?,
+                (None, None) => self
+                    .tg
+                    .edit_message_markdown(chat_id, message_id, part, markup)?,
+                (Some(last), entities) => {
+                    let reply_to = Some(&last.id);
+                    let msg = match entities {
+                        Some(entities) => self
+                            .tg
+                            .send_message_entities(chat_id, reply_to, part, entities, markup)?,
+                        None => self
+                            .tg
+                            .send_message_markdown(chat_id, reply_to, part, markup)?,
+                    };
+                    TgBot::set_message_context(&msg, &ctx);
+                    msg
+                }
+            };
+            messages.push(msg);
         }
+        Ok(messages)
     }
 
-    fn handle_settings(&self, msg: &Message) -> anyhow::Result<tg_flows::Message> {
-        self.tg.send_message_ext(
-            msg.chat.id,
-            Some(&msg.id),
-            "Choose your language model.",
-            Some(ReplyMarkup::InlineKeyboard(
-                InlineKeyboardMarkup::default()
-                    .append_row(vec![TgBotInlineButton::SettingsLMGPT35Turbo.into()])
-                    .append_row(vec![TgBotInlineButton::SettingsLMGPT35Turbo16K.into()])
-                    .append_row(vec![TgBotInlineButton::SettingsLMGPT4.into()]),
-            )),
-        )
+    /// Speak the answer in a voice message replying to the text answer, the
+    /// conversation can continue from either of them.
+    fn reply_voice(
+        &self,
+        api_key: &str,
+        voice: &str,
+        reply_to: &Message,
+        user_id: Option<UserId>,
+        chat_ctx: &TgBotContext,
+        answer: &str,
+    ) -> anyhow::Result<()> {
+        let chat_id = reply_to.chat.id;
+        if let Err(exceeded) = self.quotas.check(chat_id, user_id, openaiext::SPEECH_MODEL) {
+            bail!("{}", exceeded);
+        }
+        let speech = openaiext::speech(api_key, voice, answer);
+        usage::record_usage(
+            chat_id,
+            user_id,
+            openaiext::SPEECH_MODEL,
+            estimate_tokens(answer) as u64,
+        );
+        let speech = speech?;
+        let msg = tgext::send_voice(
+            &self.telegram_token,
+            reply_to.chat.id,
+            Some(&reply_to.id),
+            &speech,
+        )?;
+        TgBot::set_message_context(&msg, &serde_json::to_value(chat_ctx)?);
+        Ok(())
     }
 
-    fn handle_callback_query(&self, cq: &CallbackQuery) -> anyhow::Result<tg_flows::Message> {
-        if let Some(ref data) = cq.data {
-            let button: TgBotInlineButton = data.as_str().try_into()?;
-            match button {
-                TgBotInlineButton::NihongoTranslate
-                | TgBotInlineButton::NihongoExplain
-                | TgBotInlineButton::NihongoSceneMock => {
-                    self.handle_nihongo_button(cq.message.as_ref().unwrap(), &button)
-                }
-                TgBotInlineButton::NihongoSceneMockRestaurant
-                | TgBotInlineButton::NihongoSceneMockCafe
-                | TgBotInlineButton::NihongoSceneMockClothesShop
-                | TgBotInlineButton::NihongoSceneMockStreet
-                | TgBotInlineButton::NihongoSceneMockSmallTalk
-                | TgBotInlineButton::NihongoSceneMockGoBack => {
-                    self.handle_nihongo_scene_mock_button(cq.message.as_ref().unwrap(), &button)
-                }
-                TgBotInlineButton::SettingsLMGPT35Turbo
-                | TgBotInlineButton::SettingsLMGPT35Turbo16K
-                | TgBotInlineButton::SettingsLMGPT4 => {
-                    self.handle_settings_button(cq.message.as_ref().unwrap(), &button)
-                }
-            }
+    /// The model counted in the quotas and usage of an answer, follow-ups of
+    /// images are answered by the vision model instead of the language model.
+    fn quota_model(lm: &str, has_images: bool) -> String {
+        if has_images {
+            settings::vision_model()
         } else {
-            bail!("can't handle callback query without data")
+            lm.to_owned()
         }
     }
 
-    fn handle_nihongo_button(
+    /// Answer an inline query as the persona, every persona has its own
+    /// context so the answers can be generated at the same time.
+    async fn answer_inline(
         &self,
-        msg: &Message,
-        button: &TgBotInlineButton,
+        target: UsageTarget<'_>,
+        lm: &str,
+        persona_id: &str,
+        query: &str,
+    ) -> anyhow::Result<InlineAnswer> {
+        let messages = [
+            ChatMessage::system(self.personas.prompt(persona_id)),
+            ChatMessage::user(query),
+        ];
+        let answer = self
+            .complete(
+                &format!("inline--{}-{}", target.chat_id, persona_id),
+                target,
+                lm,
+                settings::api_model(lm),
+                &messages,
+                None,
+            )
+            .await?;
+        Ok(InlineAnswer {
+            persona: persona_id.to_owned(),
+            answer,
+        })
+    }
+
+    /// Answer the conversation in the message of `message_id`, and push the
+    /// answer to the conversation. The actions changing the last answer
+    /// replace it instead.
+    #[allow(clippy::too_many_arguments)]
+    async fn answer_conversation(
+        &self,
+        chat_id: ChatId,
+        user_id: Option<UserId>,
+        message_id: MessageId,
+        chat_ctx: &TgBotContext,
+        conversation: &mut Conversation,
+        lm: &str,
+        prompt: &str,
+        action: Option<AnswerAction>,
     ) -> anyhow::Result<tg_flows::Message> {
-        match button {
-            TgBotInlineButton::NihongoTranslate => self
-                .tg
-                .send_message_ext(
-                    msg.chat.id,
-                    Some(&msg.id),
-                    "日本語に翻訳しています",
-                    Some(ReplyMarkup::ForceReply(ForceReply::default())),
-                )
-                .map(|msg| self.init_message_prompt(msg, TgBotPrompt::NihongoTranslate)),
-            TgBotInlineButton::NihongoExplain => self
-                .tg
-                .send_message_ext(
-                    msg.chat.id,
-                    Some(&msg.id),
-                    "日本語の言葉を説明しています",
-                    Some(ReplyMarkup::ForceReply(ForceReply::default())),
+        // follow-ups of images must be answered by a model which can see them
+        let model = if conversation.has_images() {
+            settings::vision_model()
+        } else {
+            settings::api_model(lm).to_owned()
+        };
+        let quota_model = TgBot::quota_model(lm, conversation.has_images());
+        let target = UsageTarget {
+            chat_id,
+            user_id,
+            model: &quota_model,
+        };
+        self.fit_conversation(conversation, target, lm, &model, prompt)
+            .await;
+
+        let images = self.download_images(conversation.request_images());
+        let mut messages =
+            conversation.request_messages(prompt, images.as_deref().unwrap_or_default());
+        match action {
+            Some(AnswerAction::Continue) => messages.push(ChatMessage::user(
+                "Continue your last answer from where it stopped, don't repeat it.",
+            )),
+            Some(AnswerAction::Shorter) => messages.push(ChatMessage::user(
+                "Rewrite your last answer to be much shorter, keep the key points only.",
+            )),
+            _ => {}
+        }
+        let answer = match images {
+            Ok(_) => {
+                self.complete(
+                    &format!("ctx--{}", conversation.id),
+                    target,
+                    lm,
+                    &model,
+                    &messages,
+                    Some((chat_id, message_id)),
                 )
-                .map(|msg| self.init_message_prompt(msg, TgBotPrompt::NihongoExplain)),
-            TgBotInlineButton::NihongoSceneMock => self.tg.send_message_ext(
-                msg.chat.id,
-                Some(&msg.id),
-                "モック会話しています、何な場面をほしいですか",
-                Some(ReplyMarkup::InlineKeyboard(
-                    InlineKeyboardMarkup::default()
-                        .append_row(vec![TgBotInlineButton::NihongoSceneMockCafe.into()])
-                        .append_row(vec![TgBotInlineButton::NihongoSceneMockRestaurant.into()])
-                        .append_row(vec![TgBotInlineButton::NihongoSceneMockClothesShop.into()])
-                        .append_row(vec![TgBotInlineButton::NihongoSceneMockStreet.into()])
-                        .append_row(vec![TgBotInlineButton::NihongoSceneMockSmallTalk.into()])
-                        .append_row(vec![TgBotInlineButton::NihongoSceneMockGoBack.into()]),
-                )),
-            ),
-            _ => bail!("wrong button"),
+                .await
+            }
+            Err(e) => Err(e),
+        };
+
+        let answer = match answer {
+            Ok(answer) => answer,
+            Err(e) => {
+                log::error!("chat completion failed: {}", e);
+                // keep the question for regenerating
+                if let Err(e) = conversation.save() {
+                    log::error!("failed to save the conversation {}: {}", conversation.id, e);
+                }
+                return self.tg.edit_message_text_ext(
+                    chat_id,
+                    message_id,
+                    "Sorry, an error has occured. Please try again later.",
+                    Some(ReplyMarkup::InlineKeyboard(AnswerAction::keyboard(&[
+                        AnswerAction::Regenerate,
+                    ]))),
+                );
+            }
+        };
+
+        let prompt_tokens = messages.iter().map(|m| m.tokens()).sum::<usize>();
+        let answer = match action {
+            Some(AnswerAction::Continue) => {
+                let last = conversation.turns.pop().map(|turn| turn.text);
+                format!("{}\n\n{}", last.unwrap_or_default(), answer)
+            }
+            Some(AnswerAction::Shorter) => {
+                conversation.turns.pop();
+                answer
+            }
+            _ => answer,
+        };
+
+        let parts = self.reply_answer(chat_id, message_id, chat_ctx, &answer)?;
+        let ids: Vec<i32> = parts.iter().map(|part| part.id.0).collect();
+        conversation
+            .turns
+            .push(Turn::assistant(&answer, &ids, &model, prompt_tokens));
+        if let Err(e) = conversation.save() {
+            log::error!("failed to save the conversation {}: {}", conversation.id, e);
+        }
+
+        let last = match parts.into_iter().last() {
+            Some(last) => last,
+            None => bail!("answer {} has no parts", message_id),
+        };
+        if let (Some(voice), Some(api_key)) =
+            (self.personas.voice(&chat_ctx.prompt), &self.openai_api_key)
+        {
+            if let Err(e) = self.reply_voice(api_key, voice, &last, user_id, chat_ctx, &answer) {
+                log::warn!("failed to reply with voice: {}", e);
+            }
         }
+        Ok(last)
     }
 
-    fn handle_nihongo_scene_mock_button(
+    /// Run an action of the buttons under the last answer of a conversation,
+    /// which regenerates or changes the answer in place.
+    async fn handle_answer_action(
         &self,
+        cq: &CallbackQuery,
         msg: &Message,
-        button: &TgBotInlineButton,
-    ) -> anyhow::Result<tg_flows::Message> {
-        match button {
-            TgBotInlineButton::NihongoSceneMockCafe => self
-                .tg
-                .send_message_ext(
-                    msg.chat.id,
-                    Some(&msg.id),
-                    "カフェでいます",
-                    Some(ReplyMarkup::ForceReply(ForceReply::default())),
-                )
-                .map(|msg| self.init_message_prompt(msg, TgBotPrompt::NihongoSceneMockCafe)),
-            TgBotInlineButton::NihongoSceneMockRestaurant => self
-                .tg
-                .send_message_ext(
-                    msg.chat.id,
-                    Some(&msg.id),
-                    "レストランでいます",
-                    Some(ReplyMarkup::ForceReply(ForceReply::default())),
-                )
-                .map(|msg| self.init_message_prompt(msg, TgBotPrompt::NihongoSceneMockRestaurant)),
-            TgBotInlineButton::NihongoSceneMockClothesShop => self
-                .tg
-                .send_message_ext(
-                    msg.chat.id,
-                    Some(&msg.id),
-                    "服屋でいます",
-                    Some(ReplyMarkup::ForceReply(ForceReply::default())),
-                )
-                .map(|msg| self.init_message_prompt(msg, TgBotPrompt::NihongoSceneMockClothesShop)),
-            TgBotInlineButton::NihongoSceneMockStreet => self
+        action: AnswerAction,
+    ) -> anyhow::Result<()> {
+        let chat_ctx: TgBotContext = match store_flows::get(&TgBot::get_message_ptr(msg))
+            .and_then(|v| serde_json::from_value(v).ok())
+        {
+            Some(chat_ctx) => chat_ctx,
+            None => bail!("no context of answer {}", msg.id),
+        };
+        let mut conversation = Conversation::load(&chat_ctx.id);
+
+        let (first_id, parts) = match conversation.turns.last() {
+            Some(turn)
+                if turn.role == Role::Assistant && turn.message_ids().contains(&msg.id.0) =>
+            {
+                (turn.message_id.unwrap_or(msg.id.0), turn.parts.clone())
+            }
+            // the last question failed to be answered
+            Some(turn)
+                if turn.role == Role::User
+                    && action == AnswerAction::Regenerate
+                    && msg.reply_to_message().map(|reply| reply.id.0) == turn.message_id =>
+            {
+                (msg.id.0, vec![])
+            }
+            _ => {
+                return self
+                    .tg
+                    .answer_callback_query(&cq.id, "Only the latest answer can be changed.")
+                    .map(|_| ())
+            }
+        };
+
+        let chat_id = msg.chat.id;
+        let user_id = cq.from.id;
+        let lm = settings::resolve_language_model(chat_id, Some(user_id));
+        let quota_model = TgBot::quota_model(&lm, conversation.has_images());
+        if let Err(exceeded) = self.quotas.check(chat_id, Some(user_id), &quota_model) {
+            log::info!("quota exceeded, chat id: {}", chat_id);
+            return self
                 .tg
-                .send_message_ext(
-                    msg.chat.id,
-                    Some(&msg.id),
-                    "街でいます",
-                    Some(ReplyMarkup::ForceReply(ForceReply::default())),
-                )
-                .map(|msg| self.init_message_prompt(msg, TgBotPrompt::NihongoSceneMockStreet)),
-            TgBotInlineButton::NihongoSceneMockSmallTalk => self
+                .answer_callback_query(&cq.id, exceeded.to_string())
+                .map(|_| ());
+        }
+        log::info!("{:?} the answer {} of {}", action, first_id, chat_ctx.id);
+        self.tg.answer_callback_query(&cq.id, "")?;
+
+        for id in parts {
+            if let Err(e) = self.tg.delete_message(chat_id, MessageId(id)) {
+                log::warn!("failed to delete the part {} of the answer: {}", id, e);
+            }
+        }
+        if action == AnswerAction::Regenerate
+            && conversation.turns.last().map(|turn| turn.role) == Some(Role::Assistant)
+        {
+            conversation.turns.pop();
+        }
+        self.tg
+            .edit_message_text(chat_id, MessageId(first_id), "typing...")?;
+        // ignore callback result
+        let _ = self.set_typing(chat_id);
+
+        let question = conversation
+            .turns
+            .iter()
+            .rev()
+            .find(|turn| turn.role == Role::User)
+            .map(|turn| turn.text.clone())
+            .unwrap_or_default();
+        let prompt = self.get_prompt(&chat_ctx, &lm, &question);
+        self.answer_conversation(
+            chat_id,
+            Some(user_id),
+            MessageId(first_id),
+            &chat_ctx,
+            &mut conversation,
+            &lm,
+            &prompt,
+            Some(action),
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// Answer an edited question again, the earlier answer is edited in place
+    /// and replaces its turn in the conversation. Questions which were never
+    /// answered are left alone.
+    async fn handle_edited_message(&self, msg: &Message) -> anyhow::Result<()> {
+        let answer_id = match store_flows::get(&TgBot::get_answer_ptr(msg)).and_then(|v| v.as_i64())
+        {
+            Some(id) => MessageId(id as i32),
+            None => {
+                log::info!("edited message {} isn't answered", msg.id);
+                return Ok(());
+            }
+        };
+        if msg.voice().is_some() || msg.audio().is_some() {
+            // the question is the transcription rather than the caption
+            return Ok(());
+        }
+        let text = msg.text().or_else(|| msg.caption()).unwrap_or_default();
+        let username = self.get_bot_username();
+        let question = match command::parse_command(text) {
+            // the question of `/ask` is its arguments, other commands aren't answered
+            Some(parsed)
+                if parsed.command == Some(TgBotCommand::Ask)
+                    && parsed.is_for(username.as_deref()) =>
+            {
+                Some(parsed.args.to_owned())
+            }
+            Some(_) => return Ok(()),
+            None if msg.chat.is_group() || msg.chat.is_supergroup() => {
+                self.get_group_question(msg, text, username.as_deref())
+            }
+            None => Some(text.to_owned()),
+        };
+        let question = match question {
+            Some(question) if !question.is_empty() => question,
+            _ if msg.document().is_some() => "Summarize the document briefly.".to_owned(),
+            _ => return Ok(()),
+        };
+
+        let chat_id = msg.chat.id;
+        let user_id = msg.from().map(|u| u.id);
+        if !self.is_admin(user_id) && !self.access.is_allowed(chat_id, user_id) {
+            log::info!("access denied, chat id: {}", chat_id);
+            return Ok(());
+        }
+        let chat_ctx: TgBotContext = store_flows::get(&TgBot::get_message_ptr(msg))
+            .and_then(|v| serde_json::from_value(v).ok())
+            .unwrap_or_else(|| TgBot::get_message_context(msg));
+        let mut conversation = Conversation::load(&chat_ctx.id);
+        let lm = settings::resolve_language_model(chat_id, user_id);
+        let quota_model = TgBot::quota_model(&lm, conversation.has_images());
+        if let Err(exceeded) = self.quotas.check(chat_id, user_id, &quota_model) {
+            log::info!("quota exceeded, chat id: {}", chat_id);
+            return self
                 .tg
-                .send_message_ext(
-                    msg.chat.id,
-                    Some(&msg.id),
-                    "雑談しています",
-                    Some(ReplyMarkup::ForceReply(ForceReply::default())),
-                )
-                .map(|msg| self.init_message_prompt(msg, TgBotPrompt::NihongoSceneMockSmallTalk)),
-            TgBotInlineButton::NihongoSceneMockGoBack => self.handle_nihongo(msg, true),
-            _ => bail!("wrong button"),
+                .reply_to_message(msg, exceeded.to_string())
+                .map(|_| ());
+        }
+        let index = match conversation
+            .turns
+            .iter()
+            .position(|turn| turn.role == Role::User && turn.message_id == Some(msg.id.0))
+        {
+            Some(index) => index,
+            None => {
+                log::info!("edited message {} is out of {}", msg.id, chat_ctx.id);
+                return Ok(());
+            }
+        };
+        log::info!(
+            "answer the edited message {} again in {}",
+            msg.id,
+            answer_id
+        );
+
+        let original = conversation.clone();
+        let images = conversation.turns[index].images.clone();
+        conversation.turns[index] = Turn::user(&question, images, msg.id.0);
+        // the turns after the question are kept, except for its answer
+        let mut later = conversation.turns.split_off(index + 1);
+        if later.first().map(|turn| turn.role) == Some(Role::Assistant) {
+            for id in later.remove(0).parts {
+                if let Err(e) = self.tg.delete_message(chat_id, MessageId(id)) {
+                    log::warn!("failed to delete the part {} of the answer: {}", id, e);
+                }
+            }
+        }
+
+        self.tg.edit_message_text(chat_id, answer_id, "typing...")?;
+        // ignore callback result
+        let _ = self.set_typing(chat_id);
+
+        let prompt = self.get_prompt(&chat_ctx, &lm, &question);
+        let answer = self
+            .answer_conversation(
+                chat_id,
+                user_id,
+                answer_id,
+                &chat_ctx,
+                &mut conversation,
+                &lm,
+                &prompt,
+                None,
+            )
+            .await;
+        let answered = conversation.turns.last().map(|turn| turn.role) == Some(Role::Assistant);
+        if !answered {
+            // the edited question isn't kept without its answer, which would
+            // leave two questions in a row when more turns follow
+            conversation = original;
+        } else if !later.is_empty() {
+            conversation.turns.append(&mut later);
+        } else {
+            // saved with the answer already
+            return answer.map(|_| ());
+        }
+        if let Err(e) = conversation.save() {
+            log::error!("failed to save the conversation {}: {}", conversation.id, e);
         }
+        answer.map(|_| ())
     }
 
-    fn handle_settings_button(
+    /// The system prompt of the persona of the conversation, followed by the
+    /// parts of its documents relevant to the question which fit in the
+    /// context window of the language model.
+    fn get_prompt(&self, chat_ctx: &TgBotContext, lm: &str, question: &str) -> String {
+        let prompt = self.personas.prompt(&chat_ctx.prompt);
+        let budget = document::context_budget(
+            settings::api_model(lm),
+            estimate_tokens(&prompt) + estimate_tokens(question),
+        );
+        match document::document_context(&chat_ctx.documents, question, budget) {
+            Some(context) => format!("{}\n\n{}", prompt, context),
+            None => prompt,
+        }
+    }
+
+    /// Complete a conversation with the OpenAI API when `openai_api_key` is
+    /// set, which streams the answer into the placeholder if there is one, or
+    /// with openai-flows otherwise. Every attempt is counted in the usage of
+    /// `target`, failed ones with the prompt and what was answered so far.
+    async fn complete(
+        &self,
+        ctx_id: &str,
+        target: UsageTarget<'_>,
+        lm: &str,
+        model: &str,
+        messages: &[ChatMessage],
+        placeholder: Option<(ChatId, MessageId)>,
+    ) -> anyhow::Result<String> {
+        log::info!("complete with {}, messages: {}", model, messages.len());
+        let api_key = match self.openai_api_key {
+            Some(ref api_key) => api_key,
+            None => {
+                let (system, question) = openaiext::flatten_messages(messages);
+                let mut copt = ChatOptions::default();
+                copt.model = settings::chat_model(lm);
+                // the conversation is kept by the bot, every request starts over
+                copt.restart = true;
+                copt.system_prompt = Some(system.as_str());
+                let answer = self
+                    .openai
+                    .chat_completion(ctx_id, &question, &copt)
+                    .await
+                    .map(|resp| resp.choice)
+                    .map_err(anyhow::Error::msg);
+                let answered = answer.as_deref().unwrap_or_default();
+                TgBot::record_completion(target, messages, answered);
+                return answer;
+            }
+        };
+
+        let mut last_edit = Instant::now();
+        let mut last_len = 0;
+        let mut received = String::new();
+        let answer = openaiext::chat_completion_stream(api_key, model, messages, |partial| {
+            received.clear();
+            received.push_str(partial);
+            let (chat_id, message_id) = match placeholder {
+                Some(placeholder) if self.streaming => placeholder,
+                _ => return,
+            };
+            if partial.len() > last_len
+                && !partial.trim().is_empty()
+                && last_edit.elapsed() >= STREAM_EDIT_INTERVAL
+            {
+                // partial answers may contain unbalanced markdown, send them as plain text
+                if let Err(e) =
+                    self.tg
+                        .edit_message_text(chat_id, message_id, format!("{}…", partial))
+                {
+                    log::warn!("failed to edit streaming answer: {}", e);
+                }
+                last_edit = Instant::now();
+                last_len = partial.len();
+            }
+        });
+        TgBot::record_completion(target, messages, answer.as_deref().unwrap_or(&received));
+        answer
+    }
+
+    /// Count a completion of the messages in the usage of `target`.
+    fn record_completion(target: UsageTarget, messages: &[ChatMessage], answer: &str) {
+        let prompt_tokens = messages.iter().map(|m| m.tokens()).sum::<usize>();
+        usage::record_usage(
+            target.chat_id,
+            target.user_id,
+            target.model,
+            (prompt_tokens + estimate_tokens(answer)) as u64,
+        );
+    }
+
+    /// Keep the conversation within the context window of the model, the
+    /// dropped turns are folded into the summary of the conversation, which
+    /// is counted in the usage of `target` as the language model.
+    async fn fit_conversation(
+        &self,
+        conversation: &mut Conversation,
+        target: UsageTarget<'_>,
+        lm: &str,
+        model: &str,
+        prompt: &str,
+    ) {
+        let dropped = conversation.fit(prompt, model);
+        if dropped.is_empty() {
+            return;
+        }
+        let chat_ptr = conversation.id.as_str();
+        log::info!("drop {} turns of {}", dropped.len(), chat_ptr);
+
+        let summary_model = settings::api_model(lm);
+        let request = conversation.summary_request(&dropped, summary_model);
+        match self
+            .complete(
+                &format!("summary--{}", chat_ptr),
+                UsageTarget {
+                    model: lm,
+                    ..target
+                },
+                lm,
+                summary_model,
+                &request,
+                None,
+            )
+            .await
+        {
+            Ok(summary) => conversation.summary = Some(summary),
+            Err(e) => log::warn!("failed to summarize {}: {}", chat_ptr, e),
+        }
+    }
+
+    fn download_file(&self, file_id: &str) -> anyhow::Result<Vec<u8>> {
+        let file = self.tg.get_file(file_id)?;
+        let path = match file.file_path {
+            Some(path) => path,
+            None => bail!("file {} can't be downloaded", file.file_id),
+        };
+        let bytes = tgext::download_file(&self.telegram_token, &path)?;
+        log::info!("downloaded file: {}, {} bytes", path, bytes.len());
+        Ok(bytes)
+    }
+
+    /// Transcribe the voice or audio of the message into a question, after
+    /// the caption if there is one.
+    fn transcribe_message(&self, msg: &Message, caption: &str) -> anyhow::Result<String> {
+        let api_key = match self.openai_api_key {
+            Some(ref api_key) => api_key,
+            None => bail!("voice messages need openai_api_key"),
+        };
+        let (file_id, file_name) = match (msg.voice(), msg.audio()) {
+            (Some(voice), _) => (voice.file.id.as_str(), "voice.ogg".to_owned()),
+            (_, Some(audio)) => (
+                audio.file.id.as_str(),
+                audio.file_name.clone().unwrap_or("audio.mp3".into()),
+            ),
+            _ => bail!("no voice in message {}", msg.id),
+        };
+
+        let audio = self.download_file(file_id)?;
+        let text = openaiext::transcribe(api_key, &file_name, &audio);
+        usage::record_usage(
+            msg.chat.id,
+            msg.from().map(|u| u.id),
+            openaiext::TRANSCRIPTION_MODEL,
+            text.as_deref().map(estimate_tokens).unwrap_or_default() as u64,
+        );
+        let text = text?;
+        log::info!("transcribed {}: {}", file_name, text);
+        Ok(format!("{}\n{}", caption, text).trim().to_owned())
+    }
+
+    /// The message replied to if it isn't in a conversation yet, whose files
+    /// are read together with the message.
+    fn get_new_reply(msg: &Message) -> Option<&Message> {
+        msg.reply_to_message()
+            .filter(|reply| store_flows::get(&TgBot::get_message_ptr(reply)).is_none())
+    }
+
+    /// Extract and save the text of the documents of the message, and of the
+    /// message it replies to if that one isn't in a conversation yet. Returns
+    /// the ids of the documents.
+    fn read_message_documents(&self, msg: &Message) -> anyhow::Result<Vec<String>> {
+        let mut ids = vec![];
+        for m in TgBot::get_new_reply(msg).into_iter().chain([msg]) {
+            let doc = match m.document() {
+                Some(doc) => doc,
+                None => continue,
+            };
+            let name = doc.file_name.clone().unwrap_or("document".into());
+            let data = self.download_file(&doc.file.id)?;
+            let document = Document::extract(
+                &name,
+                doc.mime_type.as_ref().map(|mime| mime.essence_str()),
+                &data,
+            )?;
+            let id = format!("{}-{}", m.chat.id, m.id);
+            document.save(&id)?;
+            ids.push(id);
+        }
+        Ok(ids)
+    }
+
+    /// The file ids of the photos of the message, together with the photo it
+    /// replies to if that one isn't in a conversation yet.
+    fn get_message_images(msg: &Message) -> Vec<String> {
+        let mut images = vec![];
+        let reply = TgBot::get_new_reply(msg);
+        for sizes in reply.into_iter().chain([msg]).filter_map(|m| m.photo()) {
+            let photo = match sizes
+                .iter()
+                .rev()
+                .find(|size| size.width.max(size.height) <= MAX_IMAGE_SIZE)
+                .or_else(|| sizes.first())
+            {
+                Some(photo) => photo,
+                None => continue,
+            };
+            images.push(photo.file.id.clone());
+        }
+        images
+    }
+
+    /// Download the images of a conversation as `data:` urls, the urls kept
+    /// by older conversations are used as they are.
+    fn download_images(&self, images: &[String]) -> anyhow::Result<Vec<String>> {
+        images
+            .iter()
+            .map(|image| {
+                if image.starts_with("data:") {
+                    return Ok(image.clone());
+                }
+                let bytes = self.download_file(image)?;
+                Ok(format!("data:image/jpeg;base64,{}", BASE64.encode(bytes)))
+            })
+            .collect()
+    }
+
+    /// Show the menu at `path`, either by editing the menu message in place
+    /// or by replying to the message with a new one.
+    fn show_menu(
         &self,
         msg: &Message,
-        button: &TgBotInlineButton,
+        path: &str,
+        user_id: Option<UserId>,
+        edit: bool,
     ) -> anyhow::Result<tg_flows::Message> {
-        let lm = match button {
-            TgBotInlineButton::SettingsLMGPT35Turbo => "gpt3.5-turbo",
-            TgBotInlineButton::SettingsLMGPT35Turbo16K => "gpt3.5-turbo-16k",
-            TgBotInlineButton::SettingsLMGPT4 => "gpt4",
-            _ => bail!("wrong button"),
+        let text = match self.menus.resolve(path) {
+            Some(MenuNode::Menu(menu)) => menu.text(),
+            _ => bail!("unknown menu: {}", path),
         };
+        let keyboard = self
+            .menus
+            .keyboard(path, &self.personas, |target, value| {
+                self.get_setting(target, msg.chat.id, user_id).as_deref() == value
+            })
+            .unwrap_or_default();
 
-        store_flows::set(
-            "settings.language.model",
-            serde_json::Value::String(lm.to_string()),
-            None,
-        );
+        if edit {
+            self.tg.edit_message_text_ext(
+                msg.chat.id,
+                msg.id,
+                text,
+                Some(ReplyMarkup::InlineKeyboard(keyboard)),
+            )
+        } else {
+            self.tg.send_message_ext(
+                msg.chat.id,
+                Some(&msg.id),
+                text,
+                Some(ReplyMarkup::InlineKeyboard(keyboard)),
+            )
+        }
+    }
 
-        self.tg
-            .edit_message_text(msg.chat.id, msg.id, format!("Using language model: {}", lm))
+    fn handle_callback_query(&self, cq: &CallbackQuery) -> anyhow::Result<()> {
+        let (data, msg) = match (&cq.data, &cq.message) {
+            (Some(data), Some(msg)) => (data.as_str(), msg),
+            _ => {
+                log::info!("callback query without data: {}", cq.id);
+                return self
+                    .tg
+                    .answer_callback_query(&cq.id, MENU_EXPIRED)
+                    .map(|_| ());
+            }
+        };
+
+        match self.menus.resolve(data) {
+            Some(MenuNode::Menu(_)) => self
+                .show_menu(msg, data, Some(cq.from.id), true)
+                .map(|_| ()),
+            Some(MenuNode::Persona(persona_id)) => self.start_persona(msg, persona_id).map(|_| ()),
+            Some(MenuNode::Setting { target, value }) => {
+                if self
+                    .get_setting(target, msg.chat.id, Some(cq.from.id))
+                    .as_deref()
+                    == value
+                {
+                    // nothing changed, editing the menu with the same content would fail
+                    return Ok(());
+                }
+                self.set_setting(target, msg.chat.id, cq.from.id, value);
+                self.show_menu(msg, MenuTree::parent_path(data), Some(cq.from.id), true)
+                    .map(|_| ())
+            }
+            None => {
+                log::info!("expired callback data: {}", data);
+                self.tg
+                    .answer_callback_query(&cq.id, MENU_EXPIRED)
+                    .map(|_| ())
+            }
+        }
+    }
+
+    fn get_setting(
+        &self,
+        target: SettingTarget,
+        chat_id: ChatId,
+        user_id: Option<UserId>,
+    ) -> Option<String> {
+        match target {
+            SettingTarget::ChatLanguageModel => {
+                Some(settings::resolve_language_model(chat_id, None))
+            }
+            SettingTarget::UserLanguageModel => {
+                user_id.and_then(|id| SettingsScope::User(id).get_language_model())
+            }
+            SettingTarget::ChatGroupMode => Some(settings::resolve_group_mode(chat_id)),
+        }
+    }
+
+    fn set_setting(
+        &self,
+        target: SettingTarget,
+        chat_id: ChatId,
+        user_id: UserId,
+        value: Option<&str>,
+    ) {
+        match (target, value) {
+            (SettingTarget::ChatLanguageModel, Some(lm)) => {
+                SettingsScope::Chat(chat_id).set_language_model(lm)
+            }
+            (SettingTarget::ChatLanguageModel, None) => {
+                SettingsScope::Chat(chat_id).clear_language_model()
+            }
+            (SettingTarget::UserLanguageModel, Some(lm)) => {
+                SettingsScope::User(user_id).set_language_model(lm)
+            }
+            (SettingTarget::UserLanguageModel, None) => {
+                SettingsScope::User(user_id).clear_language_model()
+            }
+            (SettingTarget::ChatGroupMode, Some(mode)) => {
+                SettingsScope::Chat(chat_id).set_group_mode(mode)
+            }
+            (SettingTarget::ChatGroupMode, None) => {}
+        }
     }
 
     fn get_message_ptr(msg: &Message) -> String {
         format!("ptr--{}-{}", msg.chat.id, msg.id)
     }
 
+    /// Key of the answer to a question, which is edited when the question is.
+    fn get_answer_ptr(msg: &Message) -> String {
+        format!("answer--{}-{}", msg.chat.id, msg.id)
+    }
+
     fn get_root_message(msg: &Message) -> &Message {
         let mut root = msg;
         while root.reply_to_message().is_some() {
@@ -650,6 +1664,17 @@ impl TgBot {
         root
     }
 
+    fn get_message_context(msg: &Message) -> TgBotContext {
+        let root_ptr = TgBot::get_message_ptr(TgBot::get_root_message(msg));
+        store_flows::get(&root_ptr)
+            .and_then(|v| serde_json::from_value(v).ok())
+            .unwrap_or(TgBotContext {
+                id: root_ptr,
+                prompt: DEFAULT_PERSONA.to_owned(),
+                documents: vec![],
+            })
+    }
+
     fn set_message_context(msg: &Message, ctx: &serde_json::Value) {
         let mut root = msg;
         loop {
@@ -664,10 +1689,28 @@ impl TgBot {
         }
     }
 
-    fn init_message_prompt(&self, msg: Message, prompt: TgBotPrompt) -> Message {
+    fn start_persona(&self, msg: &Message, persona_id: &str) -> anyhow::Result<tg_flows::Message> {
+        let persona = self.personas.resolve(persona_id);
+        let entry = if persona.entry.is_empty() {
+            persona.title.as_str()
+        } else {
+            persona.entry.as_str()
+        };
+        self.tg
+            .send_message_ext(
+                msg.chat.id,
+                Some(&msg.id),
+                entry,
+                Some(ReplyMarkup::ForceReply(ForceReply::default())),
+            )
+            .map(|msg| self.init_message_prompt(msg, &persona.id))
+    }
+
+    fn init_message_prompt(&self, msg: Message, persona_id: &str) -> Message {
         let ctx = serde_json::to_value(TgBotContext {
             id: Self::get_message_ptr(&msg),
-            prompt,
+            prompt: persona_id.to_owned(),
+            documents: vec![],
         })
         .unwrap();
         Self::set_message_context(&msg, &ctx);
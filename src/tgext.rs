@@ -1,5 +1,70 @@
-use crate::markdown::escape_markdown;
-use tg_flows::{BotCommand, ChatId, Message, MessageId, ReplyMarkup, Telegram};
+use crate::markdown::{self, MessageEntity, ParseMode};
+use crate::multipart::Multipart;
+use crate::settings;
+use http_req::{
+    request::{self, Method, Request},
+    uri::Uri,
+};
+use serde::Deserialize;
+use tg_flows::{
+    BotCommand, BotCommandScope, ChatId, Message, MessageId, ReplyMarkup, Telegram, User,
+};
+
+/// A file ready to be downloaded, see [`download_file`].
+#[derive(Debug, Deserialize)]
+pub struct TgFile {
+    pub file_id: String,
+    pub file_size: Option<u64>,
+    pub file_path: Option<String>,
+}
+
+/// Download a file of `get_file`, the link contains the token of the bot so
+/// it must not be shared.
+pub fn download_file(telegram_token: &str, file_path: &str) -> anyhow::Result<Vec<u8>> {
+    let url = format!(
+        "https://api.telegram.org/file/bot{}/{}",
+        telegram_token, file_path
+    );
+    let mut body = vec![];
+    let resp = request::get(&url, &mut body)?;
+    if !resp.status_code().is_success() {
+        anyhow::bail!("failed to download {}: {}", file_path, resp.status_code());
+    }
+    Ok(body)
+}
+
+/// Send a voice message of an OGG file encoded with OPUS, files can only be
+/// uploaded as `multipart/form-data`.
+pub fn send_voice(
+    telegram_token: &str,
+    chat_id: ChatId,
+    reply_to: Option<&MessageId>,
+    voice: &[u8],
+) -> anyhow::Result<Message> {
+    let url = format!("https://api.telegram.org/bot{}/sendVoice", telegram_token);
+    let mut form = Multipart::default().text("chat_id", &chat_id.to_string());
+    if let Some(id) = reply_to {
+        form = form.text("reply_to_message_id", &id.0.to_string());
+    }
+    let (content_type, body) = form.file("voice", "voice.ogg", "audio/ogg", voice).finish();
+
+    let mut writer = vec![];
+    let resp = Request::new(&Uri::try_from(url.as_str())?)
+        .method(Method::POST)
+        .header("Content-Type", &content_type)
+        .header("Content-Length", &body.len())
+        .body(&body)
+        .send(&mut writer)?;
+    if !resp.status_code().is_success() {
+        anyhow::bail!(
+            "failed to send voice: {} {}",
+            resp.status_code(),
+            String::from_utf8_lossy(&writer)
+        );
+    }
+    let mut resp: serde_json::Value = serde_json::from_slice(&writer)?;
+    Ok(serde_json::from_value(resp["result"].take())?)
+}
 
 pub trait TgExt {
     fn reply_to_message<T>(&self, msg: &Message, text: T) -> anyhow::Result<Message>
@@ -11,6 +76,32 @@ pub trait TgExt {
         T: IntoIterator,
         T::Item: Into<BotCommand>;
 
+    fn set_my_commands_with_scope<T>(
+        &self,
+        cmds: T,
+        scope: BotCommandScope,
+    ) -> anyhow::Result<bool>
+    where
+        T: IntoIterator,
+        T::Item: Into<BotCommand>;
+
+    fn answer_callback_query<T>(&self, callback_query_id: &str, text: T) -> anyhow::Result<bool>
+    where
+        T: Into<String>;
+
+    fn get_me(&self) -> anyhow::Result<User>;
+
+    fn get_file(&self, file_id: &str) -> anyhow::Result<TgFile>;
+
+    fn answer_inline_query(
+        &self,
+        inline_query_id: &str,
+        results: Vec<serde_json::Value>,
+        cache_time: u32,
+    ) -> anyhow::Result<bool>;
+
+    fn delete_message(&self, chat_id: ChatId, message_id: MessageId) -> anyhow::Result<bool>;
+
     fn send_message_ext<T>(
         &self,
         chat_id: ChatId,
@@ -30,6 +121,46 @@ pub trait TgExt {
     ) -> anyhow::Result<Message>
     where
         T: Into<String>;
+
+    /// Send text converted in the parse mode of [`settings::parse_mode`], it's
+    /// sent as plain text if Telegram rejects it.
+    fn send_message_markdown(
+        &self,
+        chat_id: ChatId,
+        reply_to: Option<&MessageId>,
+        escaped: String,
+        reply_markup: Option<ReplyMarkup>,
+    ) -> anyhow::Result<Message>;
+
+    /// Edit a message with text converted like [`TgExt::send_message_markdown`].
+    fn edit_message_markdown(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+        escaped: String,
+        reply_markup: Option<ReplyMarkup>,
+    ) -> anyhow::Result<Message>;
+
+    /// Send plain text formatted by `entities` instead of a parse mode, it's
+    /// sent without them if Telegram rejects them.
+    fn send_message_entities(
+        &self,
+        chat_id: ChatId,
+        reply_to: Option<&MessageId>,
+        text: String,
+        entities: Vec<MessageEntity>,
+        reply_markup: Option<ReplyMarkup>,
+    ) -> anyhow::Result<Message>;
+
+    /// Edit a message with text formatted like [`TgExt::send_message_entities`].
+    fn edit_message_text_entities(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+        text: String,
+        entities: Vec<MessageEntity>,
+        reply_markup: Option<ReplyMarkup>,
+    ) -> anyhow::Result<Message>;
 }
 
 impl TgExt for Telegram {
@@ -60,6 +191,76 @@ impl TgExt for Telegram {
         self.request(tg_flows::Method::SetMyCommands, body.to_string().as_bytes())
     }
 
+    fn set_my_commands_with_scope<T>(&self, cmds: T, scope: BotCommandScope) -> anyhow::Result<bool>
+    where
+        T: IntoIterator,
+        T::Item: Into<BotCommand>,
+    {
+        let commands: Vec<BotCommand> = cmds.into_iter().map(|cmd| cmd.into()).collect();
+        let body = serde_json::json!({
+            "commands": commands,
+            "scope": scope,
+        });
+        log::info!("set bot command with scope: {}", body);
+        self.request(tg_flows::Method::SetMyCommands, body.to_string().as_bytes())
+    }
+
+    fn answer_callback_query<T>(&self, callback_query_id: &str, text: T) -> anyhow::Result<bool>
+    where
+        T: Into<String>,
+    {
+        let text: String = text.into();
+        let body = serde_json::json!({
+            "callback_query_id": callback_query_id,
+            "text": text,
+        });
+        log::info!("answer callback query: {}", body);
+        self.request(
+            tg_flows::Method::AnswerCallbackQuery,
+            body.to_string().as_bytes(),
+        )
+    }
+
+    fn get_me(&self) -> anyhow::Result<User> {
+        self.request(tg_flows::Method::GetMe, "{}".as_bytes())
+    }
+
+    fn get_file(&self, file_id: &str) -> anyhow::Result<TgFile> {
+        let body = serde_json::json!({
+            "file_id": file_id,
+        });
+        log::info!("get file: {}", body);
+        self.request(tg_flows::Method::GetFile, body.to_string().as_bytes())
+    }
+
+    fn answer_inline_query(
+        &self,
+        inline_query_id: &str,
+        results: Vec<serde_json::Value>,
+        cache_time: u32,
+    ) -> anyhow::Result<bool> {
+        let body = serde_json::json!({
+            "inline_query_id": inline_query_id,
+            "results": results,
+            "cache_time": cache_time,
+            "is_personal": true,
+        });
+        log::info!("answer inline query: {}", body);
+        self.request(
+            tg_flows::Method::AnswerInlineQuery,
+            body.to_string().as_bytes(),
+        )
+    }
+
+    fn delete_message(&self, chat_id: ChatId, message_id: MessageId) -> anyhow::Result<bool> {
+        let body = serde_json::json!({
+            "chat_id": chat_id,
+            "message_id": message_id.0,
+        });
+        log::info!("delete message: {}", body);
+        self.request(tg_flows::Method::DeleteMessage, body.to_string().as_bytes())
+    }
+
     fn send_message_ext<T>(
         &self,
         chat_id: ChatId,
@@ -70,6 +271,11 @@ impl TgExt for Telegram {
     where
         T: Into<String>,
     {
+        let mode = settings::parse_mode();
+        if mode == ParseMode::Entities {
+            let (text, entities) = markdown::render_entities(text.into());
+            return self.send_message_entities(chat_id, reply_to, text, entities, reply_markup);
+        }
         let markup_value = match reply_markup {
             Some(markup) => serde_json::to_value(markup)?,
             _ => serde_json::Value::Null,
@@ -81,8 +287,8 @@ impl TgExt for Telegram {
         let body = serde_json::json!({
             "chat_id": chat_id,
             "reply_to_message_id": message_id,
-            "parse_mode": "MarkdownV2",
-            "text": escape_markdown(text.into())?,
+            "parse_mode": mode.name(),
+            "text": mode.render(text.into()),
             "reply_markup": markup_value,
         });
         log::info!("send message ext: {}", &body);
@@ -99,20 +305,31 @@ impl TgExt for Telegram {
     where
         T: Into<String>,
     {
-        let text: String = text.into();
+        let mode = settings::parse_mode();
+        if mode == ParseMode::Entities {
+            let (text, entities) = markdown::render_entities(text.into());
+            return self.edit_message_text_entities(
+                chat_id,
+                message_id,
+                text,
+                entities,
+                reply_markup,
+            );
+        }
+        let text = mode.render(text.into());
         let body = match reply_markup {
             Some(markup) => serde_json::json!({
                 "chat_id": chat_id,
                 "message_id": message_id.0,
-                "parse_mode": "MarkdownV2",
-                "text": escape_markdown(&text)?,
+                "parse_mode": mode.name(),
+                "text": text,
                 "reply_markup": serde_json::to_value(markup)?,
             }),
             _ => serde_json::json!({
                 "chat_id": chat_id,
                 "message_id": message_id.0,
-                "parse_mode": "MarkdownV2",
-                "text": escape_markdown(&text)?,
+                "parse_mode": mode.name(),
+                "text": text,
             }),
         };
         match self.request(
@@ -120,8 +337,165 @@ impl TgExt for Telegram {
             body.to_string().as_bytes(),
         ) {
             Err(_) => {
-                log::error!("wrong escape_markdown: {}", body);
-                self.edit_message_text(chat_id, message_id, text)
+                log::error!("wrong {:?} text: {}", mode, body);
+                self.edit_message_text(chat_id, message_id, mode.unescape(&text))
+            }
+            res => res,
+        }
+    }
+
+    fn send_message_markdown(
+        &self,
+        chat_id: ChatId,
+        reply_to: Option<&MessageId>,
+        escaped: String,
+        reply_markup: Option<ReplyMarkup>,
+    ) -> anyhow::Result<Message> {
+        let mode = settings::parse_mode();
+        let markup_value = match reply_markup {
+            Some(markup) => serde_json::to_value(markup)?,
+            _ => serde_json::Value::Null,
+        };
+        let message_id = match reply_to {
+            Some(id) => serde_json::to_value(id)?,
+            _ => serde_json::Value::Null,
+        };
+        let body = serde_json::json!({
+            "chat_id": chat_id,
+            "reply_to_message_id": message_id,
+            "parse_mode": mode.name(),
+            "text": escaped,
+            "reply_markup": markup_value,
+        });
+        match self.request(tg_flows::Method::SendMessage, body.to_string().as_bytes()) {
+            Err(_) => {
+                log::error!("wrong {:?} text: {}", mode, body);
+                let body = serde_json::json!({
+                    "chat_id": chat_id,
+                    "reply_to_message_id": message_id,
+                    "text": mode.unescape(&escaped),
+                    "reply_markup": markup_value,
+                });
+                self.request(tg_flows::Method::SendMessage, body.to_string().as_bytes())
+            }
+            res => res,
+        }
+    }
+
+    fn edit_message_markdown(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+        escaped: String,
+        reply_markup: Option<ReplyMarkup>,
+    ) -> anyhow::Result<Message> {
+        let mode = settings::parse_mode();
+        let markup_value = match reply_markup {
+            Some(markup) => serde_json::to_value(markup)?,
+            _ => serde_json::Value::Null,
+        };
+        let body = serde_json::json!({
+            "chat_id": chat_id,
+            "message_id": message_id.0,
+            "parse_mode": mode.name(),
+            "text": escaped,
+            "reply_markup": markup_value,
+        });
+        match self.request(
+            tg_flows::Method::EditMessageText,
+            body.to_string().as_bytes(),
+        ) {
+            Err(_) => {
+                log::error!("wrong {:?} text: {}", mode, body);
+                let body = serde_json::json!({
+                    "chat_id": chat_id,
+                    "message_id": message_id.0,
+                    "text": mode.unescape(&escaped),
+                    "reply_markup": markup_value,
+                });
+                self.request(
+                    tg_flows::Method::EditMessageText,
+                    body.to_string().as_bytes(),
+                )
+            }
+            res => res,
+        }
+    }
+
+    fn send_message_entities(
+        &self,
+        chat_id: ChatId,
+        reply_to: Option<&MessageId>,
+        text: String,
+        entities: Vec<MessageEntity>,
+        reply_markup: Option<ReplyMarkup>,
+    ) -> anyhow::Result<Message> {
+        let markup_value = match reply_markup {
+            Some(markup) => serde_json::to_value(markup)?,
+            _ => serde_json::Value::Null,
+        };
+        let message_id = match reply_to {
+            Some(id) => serde_json::to_value(id)?,
+            _ => serde_json::Value::Null,
+        };
+        let body = serde_json::json!({
+            "chat_id": chat_id,
+            "reply_to_message_id": message_id,
+            "text": text,
+            "entities": entities,
+            "reply_markup": markup_value,
+        });
+        log::info!("send message entities: {}", &body);
+        match self.request(tg_flows::Method::SendMessage, body.to_string().as_bytes()) {
+            Err(_) => {
+                log::error!("wrong entities: {}", body);
+                let body = serde_json::json!({
+                    "chat_id": chat_id,
+                    "reply_to_message_id": message_id,
+                    "text": text,
+                    "reply_markup": markup_value,
+                });
+                self.request(tg_flows::Method::SendMessage, body.to_string().as_bytes())
+            }
+            res => res,
+        }
+    }
+
+    fn edit_message_text_entities(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+        text: String,
+        entities: Vec<MessageEntity>,
+        reply_markup: Option<ReplyMarkup>,
+    ) -> anyhow::Result<Message> {
+        let markup_value = match reply_markup {
+            Some(markup) => serde_json::to_value(markup)?,
+            _ => serde_json::Value::Null,
+        };
+        let body = serde_json::json!({
+            "chat_id": chat_id,
+            "message_id": message_id.0,
+            "text": text,
+            "entities": entities,
+            "reply_markup": markup_value,
+        });
+        match self.request(
+            tg_flows::Method::EditMessageText,
+            body.to_string().as_bytes(),
+        ) {
+            Err(_) => {
+                log::error!("wrong entities: {}", body);
+                let body = serde_json::json!({
+                    "chat_id": chat_id,
+                    "message_id": message_id.0,
+                    "text": text,
+                    "reply_markup": markup_value,
+                });
+                self.request(
+                    tg_flows::Method::EditMessageText,
+                    body.to_string().as_bytes(),
+                )
             }
             res => res,
         }
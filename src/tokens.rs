@@ -0,0 +1,21 @@
+/// Tokens of an image in high detail, which is 1024x1024 pixels or smaller.
+pub const IMAGE_TOKENS: usize = 765;
+
+/// Tokens every message takes besides its content.
+pub const MESSAGE_OVERHEAD_TOKENS: usize = 4;
+
+/// Roughly estimate the number of tokens of a text without a tokenizer, an
+/// english word is around 4 characters per token while CJK characters are
+/// usually one token each.
+pub fn estimate_tokens(text: &str) -> usize {
+    let mut ascii: usize = 0;
+    let mut others = 0;
+    for c in text.chars() {
+        if c.is_ascii() {
+            ascii += 1;
+        } else {
+            others += 1;
+        }
+    }
+    ascii.div_ceil(4) + others
+}
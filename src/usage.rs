@@ -0,0 +1,252 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use tg_flows::{ChatId, UserId};
+
+#[derive(Clone, Copy, Debug)]
+pub enum UsageScope {
+    Deployment,
+    Chat(ChatId),
+    User(UserId),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UsageWindow {
+    Daily,
+    Monthly,
+}
+
+impl UsageWindow {
+    fn period(&self, now: DateTime<Utc>) -> String {
+        match self {
+            UsageWindow::Daily => now.format("%Y-%m-%d").to_string(),
+            UsageWindow::Monthly => now.format("%Y-%m").to_string(),
+        }
+    }
+
+    /// The time when the usage of the current window is reset.
+    pub fn reset_at(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let date = match self {
+            UsageWindow::Daily => now.date_naive() + Duration::days(1),
+            UsageWindow::Monthly if now.month() == 12 => {
+                NaiveDate::from_ymd_opt(now.year() + 1, 1, 1).unwrap()
+            }
+            UsageWindow::Monthly => {
+                NaiveDate::from_ymd_opt(now.year(), now.month() + 1, 1).unwrap()
+            }
+        };
+        date.and_hms_opt(0, 0, 0).unwrap().and_utc()
+    }
+}
+
+impl std::fmt::Display for UsageWindow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UsageWindow::Daily => write!(f, "daily"),
+            UsageWindow::Monthly => write!(f, "monthly"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct Usage {
+    pub requests: u64,
+    pub tokens: u64,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Limits {
+    pub daily_requests: Option<u64>,
+    pub daily_tokens: Option<u64>,
+    pub monthly_requests: Option<u64>,
+    pub monthly_tokens: Option<u64>,
+}
+
+impl Limits {
+    fn get(&self, window: UsageWindow) -> (Option<u64>, Option<u64>) {
+        match window {
+            UsageWindow::Daily => (self.daily_requests, self.daily_tokens),
+            UsageWindow::Monthly => (self.monthly_requests, self.monthly_tokens),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+struct ModelQuota {
+    #[serde(default)]
+    user: Limits,
+    #[serde(default)]
+    chat: Limits,
+}
+
+pub struct QuotaExceeded {
+    pub model: String,
+    pub window: UsageWindow,
+    pub reset_at: DateTime<Utc>,
+}
+
+impl std::fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Sorry, the {} quota of {} has been used up, it will be reset at {}.",
+            self.window,
+            self.model,
+            self.reset_at.format("%Y-%m-%d %H:%M UTC")
+        )
+    }
+}
+
+/// Quotas of every language model, configured with the `quotas` environment
+/// variable in JSON, e.g.
+/// `{"gpt4": {"user": {"daily_tokens": 20000}, "chat": {"monthly_requests": 1000}}}`.
+/// Language models without quotas are unlimited.
+pub struct Quotas {
+    models: HashMap<String, ModelQuota>,
+}
+
+impl Quotas {
+    pub fn from_env() -> Self {
+        let models = std::env::var("quotas")
+            .ok()
+            .and_then(|text| {
+                serde_json::from_str(&text)
+                    .map_err(|e| log::error!("failed to parse quotas: {}", e))
+                    .ok()
+            })
+            .unwrap_or_default();
+        Self { models }
+    }
+
+    pub fn limits(&self, scope: UsageScope, model: &str) -> Limits {
+        self.models
+            .get(model)
+            .and_then(|quota| match scope {
+                UsageScope::Deployment => None,
+                UsageScope::Chat(_) => Some(quota.chat.clone()),
+                UsageScope::User(_) => Some(quota.user.clone()),
+            })
+            .unwrap_or_default()
+    }
+
+    /// Check the quotas of the chat and the user before a request.
+    pub fn check(
+        &self,
+        chat_id: ChatId,
+        user_id: Option<UserId>,
+        model: &str,
+    ) -> Result<(), QuotaExceeded> {
+        let now = Utc::now();
+        let scopes = user_id
+            .map(UsageScope::User)
+            .into_iter()
+            .chain([UsageScope::Chat(chat_id)]);
+        for scope in scopes {
+            let limits = self.limits(scope, model);
+            for window in [UsageWindow::Daily, UsageWindow::Monthly] {
+                let usage = get_usage(scope, model, window, now);
+                let (requests, tokens) = limits.get(window);
+                if requests.map(|r| usage.requests >= r).unwrap_or(false)
+                    || tokens.map(|t| usage.tokens >= t).unwrap_or(false)
+                {
+                    return Err(QuotaExceeded {
+                        model: model.to_owned(),
+                        window,
+                        reset_at: window.reset_at(now),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn usage_key(scope: UsageScope, model: &str, window: UsageWindow, now: DateTime<Utc>) -> String {
+    let scope = match scope {
+        UsageScope::Deployment => "deployment".to_owned(),
+        UsageScope::Chat(id) => format!("chat-{}", id),
+        UsageScope::User(id) => format!("user-{}", id),
+    };
+    format!("usage--{}.{}.{}", scope, model, window.period(now))
+}
+
+pub fn get_usage(scope: UsageScope, model: &str, window: UsageWindow, now: DateTime<Utc>) -> Usage {
+    store_flows::get(&usage_key(scope, model, window, now))
+        .and_then(|v| serde_json::from_value(v).ok())
+        .unwrap_or_default()
+}
+
+/// Count a request and its tokens in every window of the chat, the user and
+/// the whole deployment.
+pub fn record_usage(chat_id: ChatId, user_id: Option<UserId>, model: &str, tokens: u64) {
+    let now = Utc::now();
+    let scopes = user_id
+        .map(UsageScope::User)
+        .into_iter()
+        .chain([UsageScope::Chat(chat_id), UsageScope::Deployment]);
+    for scope in scopes {
+        for window in [UsageWindow::Daily, UsageWindow::Monthly] {
+            let key = usage_key(scope, model, window, now);
+            let mut usage = get_usage(scope, model, window, now);
+            usage.requests += 1;
+            usage.tokens += tokens;
+            log::info!("record usage, key: {}, usage: {:?}", key, usage);
+            store_flows::set(&key, serde_json::to_value(usage).unwrap(), None);
+        }
+    }
+}
+
+const KNOWN_CHATS_KEY: &str = "usage--chats";
+
+/// Remember the chat so that admins can broadcast to it. The list is checked
+/// on every message, so a chat lost by a concurrent update is added back the
+/// next time it talks to the bot.
+pub fn record_chat(chat_id: ChatId) {
+    let mut chats = known_chats();
+    if !chats.contains(&chat_id.0) {
+        chats.push(chat_id.0);
+        store_flows::set(KNOWN_CHATS_KEY, serde_json::to_value(chats).unwrap(), None);
+    }
+}
+
+pub fn known_chats() -> Vec<i64> {
+    store_flows::get(KNOWN_CHATS_KEY)
+        .and_then(|v| serde_json::from_value(v).ok())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(text: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(text)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    #[test]
+    fn daily_reset_at_next_midnight() {
+        let reset = UsageWindow::Daily.reset_at(at("2024-02-28T23:59:59Z"));
+        assert_eq!(reset, at("2024-02-29T00:00:00Z"));
+        let reset = UsageWindow::Daily.reset_at(at("2024-12-31T00:00:00Z"));
+        assert_eq!(reset, at("2025-01-01T00:00:00Z"));
+    }
+
+    #[test]
+    fn monthly_reset_at_first_of_next_month() {
+        let reset = UsageWindow::Monthly.reset_at(at("2024-01-31T12:00:00Z"));
+        assert_eq!(reset, at("2024-02-01T00:00:00Z"));
+        let reset = UsageWindow::Monthly.reset_at(at("2024-12-01T00:00:00Z"));
+        assert_eq!(reset, at("2025-01-01T00:00:00Z"));
+    }
+
+    #[test]
+    fn reset_at_starts_the_next_period() {
+        let now = at("2024-12-31T23:59:59Z");
+        for window in [UsageWindow::Daily, UsageWindow::Monthly] {
+            assert_ne!(window.period(window.reset_at(now)), window.period(now));
+        }
+    }
+}